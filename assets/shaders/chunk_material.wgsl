@@ -4,6 +4,8 @@
 struct Vertex {
     [[location(0)]] position: vec3<f32>;    
     [[location(1)]] normal: vec3<f32>;    
+    [[location(2)]] gradient: f32;
+    [[location(3)]] occlusion: f32;
 };
 
 
@@ -12,6 +14,7 @@ struct VertexOutput {
     [[location(0)]] color: vec4<f32>;
     [[location(1)]] world_position: vec4<f32>;
     [[location(2)]] world_normal: vec3<f32>;
+    [[location(3)]] occlusion: f32;
 };
 
 fn inverse_transpose_3x3(in: mat3x3<f32>) -> mat3x3<f32> {
@@ -51,6 +54,7 @@ fn vertex(vertex: Vertex) -> VertexOutput {
     out.color = vec4<f32>(0.5, height , 1.0 - height, 1.0);
     out.world_normal = skin_normals(mesh.model, vertex.normal);
     out.world_position = world_position;
+    out.occlusion = vertex.occlusion;
     
     return out;
 }
@@ -61,5 +65,5 @@ fn fragment(input: VertexOutput) -> [[location(0)]] vec4<f32> {
     let lightdir = normalize(vec3<f32>(100.0, 50.0, -500.0) - input.world_position.xyz);
     let diff = max(dot(norm, lightdir), 0.0);
 
-    return vec4<f32>(input.color.xyz * (diff + 0.1), 1.0);
+    return vec4<f32>(input.color.xyz * (diff + 0.1) * mix(0.4, 1.0, input.occlusion), 1.0);
 }
\ No newline at end of file
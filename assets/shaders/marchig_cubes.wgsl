@@ -1,21 +1,74 @@
+// position.w is the length of the density gradient, normal.w how open the surface around the vertex is
+struct Vertex {
+    position: vec4<f32>;
+    normal: vec4<f32>;
+};
+
+// indices into the vertex buffer
 struct Triangle {
-    a : vec4<f32>;
-    b : vec4<f32>;
-    c : vec4<f32>;
+    a: u32;
+    b: u32;
+    c: u32;
 };
 
-struct Atomics {
+// vertex and triangle count of one chunk
+struct ChunkAtomics {
+    vert_head: atomic<u32>;
     tri_head: atomic<u32>;
 };
 
+struct Atomics {
+    chunks: array<ChunkAtomics>;
+};
+
 struct Points {
     data : [[stride(4)]] array<f32>;
 };
 
+struct Vertices {
+    vertices: array<Vertex>;
+};
+
 struct Triangles {
     triangles: array<Triangle>; 
 };
 
+struct EdgeVertices {
+    data : [[stride(4)]] array<u32>;
+};
+
+// arguments of draw_indexed_indirect
+struct DrawArgs {
+    index_count: u32;
+    instance_count: u32;
+    first_index: u32;
+    base_vertex: i32;
+    first_instance: u32;
+};
+
+struct DrawArgsBuffer {
+    args: array<DrawArgs>;
+};
+
+struct Offsets {
+    data: [[stride(4)]] array<u32>;
+};
+
+// samples along one axis of the chunk, padding included,
+// and how many vertices and triangles each chunk of a batch has room for.
+// ordered is set when count and scan ran, edges and march then write to the scanned offsets
+// instead of appending, so the output order only depends on the density field.
+// the surface lies where the density equals iso_level. decider switches from tri_table to
+// the loops of face_loops
+struct Dims {
+    axis_size: i32;
+    max_vertices: u32;
+    max_triangles: u32;
+    ordered: u32;
+    iso_level: f32;
+    decider: u32;
+};
+
 [[group(0), binding(0)]]
 var<storage, read> points: Points;
 
@@ -23,15 +76,60 @@ var<storage, read> points: Points;
 var<storage, read_write> global_atomics: Atomics;
 
 [[group(0), binding(2)]]
+var<storage, read_write> vertices: Vertices;
+
+[[group(0), binding(3)]]
 var<storage, read_write> triangles: Triangles;
 
+// vertex index of every edge crossing, written by edges and read by march
+[[group(0), binding(4)]]
+var<storage, read_write> edge_vertices: EdgeVertices;
+
+[[group(0), binding(5)]]
+var<uniform> dims: Dims;
+
+// one per chunk, only used when the meshes stay on the gpu
+[[group(0), binding(6)]]
+var<storage, read_write> draw_args: DrawArgsBuffer;
+
+// crossings and triangles per sample, written by count and turned into offsets by scan
+[[group(0), binding(7)]]
+var<storage, read_write> vert_offsets: Offsets;
+
+[[group(0), binding(8)]]
+var<storage, read_write> tri_offsets: Offsets;
+
+
+// samples around the meshed region that only feed the gradient, shared with the neighbouring chunks
+let padding: i32 = 1;
+
+// the chunks of a batch are stacked along z, each one workgroup aligned.
+// every chunk has its own slice of the buffers, this is the chunk the current invocation works on
+var<private> chunk: i32;
+
+fn split_batch(id: vec3<u32>) -> vec3<i32> {
+    let stride = (dims.axis_size + 7) / 8 * 8;
+    let id = vec3<i32>(id);
+    chunk = id.z / stride;
+    return vec3<i32>(id.x, id.y, id.z % stride);
+}
 
 fn to_index(pos: vec3<i32>) -> i32 {
-    return i32(pos.x << 0u | pos.y << 10u | pos.z << 5u);
+    let chunk_offset = chunk * dims.axis_size * dims.axis_size * dims.axis_size;
+    return chunk_offset + pos.x + pos.z * dims.axis_size + pos.y * dims.axis_size * dims.axis_size;
+}
+
+fn to_edge(pos: vec3<i32>, axis: i32) -> i32 {
+    return to_index(pos) * 3 + axis;
+}
+
+// relative to the iso level, so everything else can look for the surface at 0
+fn density(pos: vec3<i32>) -> f32 {
+    return points.data[to_index(pos)] - dims.iso_level;
 }
 
 fn to_corner(pos: vec3<i32>) -> vec4<f32> {
-    return vec4<f32>(vec3<f32>(pos), f32(points.data[to_index(pos)]));
+    return vec4<f32>(vec3<f32>(pos), density(pos));
 }
 
 fn interpolate_verts(v1: vec4<f32>, v2: vec4<f32>) -> vec3<f32>{
@@ -40,39 +138,79 @@ fn interpolate_verts(v1: vec4<f32>, v2: vec4<f32>) -> vec3<f32>{
     return v1.xyz + t * (v2.xyz - v1.xyz);
 }
 
+// central difference of the density field, points towards the inside of the surface
+fn gradient(pos: vec3<i32>) -> vec3<f32> {
+    return vec3<f32>(
+        density(pos + vec3<i32>(1, 0, 0)) - density(pos - vec3<i32>(1, 0, 0)),
+        density(pos + vec3<i32>(0, 1, 0)) - density(pos - vec3<i32>(0, 1, 0)),
+        density(pos + vec3<i32>(0, 0, 1)) - density(pos - vec3<i32>(0, 0, 1)),
+    ) * 0.5;
+}
 
+fn interpolate_gradient(v1: vec4<f32>, v2: vec4<f32>) -> vec3<f32> {
+    let t = (0.0 - v1.w) / (v2.w - v1.w);
+    return mix(gradient(vec3<i32>(v1.xyz)), gradient(vec3<i32>(v2.xyz)), t);
+}
 
-var<private> corner_index_afrom_edge: array<i32,12> = array<i32,12>(
+// 1 on a flat surface, less in creases and holes. counts the solid samples around the
+// corner inside the surface, half of them being solid is as open as it gets
+fn occlusion(pos: vec3<i32>) -> f32 {
+    var solid = 0.0;
+    for (var x = -1; x <= 1; x = x + 1) {
+        for (var y = -1; y <= 1; y = y + 1) {
+            for (var z = -1; z <= 1; z = z + 1) {
+                solid = solid + f32(density(pos + vec3<i32>(x, y, z)) >= 0.0);
+            }
+        }
+    }
+
+    return clamp(2.0 - solid / 13.5, 0.0, 1.0);
+}
+
+
+var<private> axis_offset: array<vec3<i32>,3> = array<vec3<i32>,3>(
+    vec3<i32>(1, 0, 0),
+    vec3<i32>(0, 1, 0),
+    vec3<i32>(0, 0, 1)
+);
+
+// every cube edge as the corner it starts at plus an axis,
+// so neighbouring cells end up with the same key for a shared edge
+var<private> edge_origin: array<vec3<i32>,12> = array<vec3<i32>,12>(
+    vec3<i32>(0, 0, 0),
+    vec3<i32>(1, 0, 0),
+    vec3<i32>(0, 0, 1),
+    vec3<i32>(0, 0, 0),
+    vec3<i32>(0, 1, 0),
+    vec3<i32>(1, 1, 0),
+    vec3<i32>(0, 1, 1),
+    vec3<i32>(0, 1, 0),
+    vec3<i32>(0, 0, 0),
+    vec3<i32>(1, 0, 0),
+    vec3<i32>(1, 0, 1),
+    vec3<i32>(0, 0, 1)
+);
+
+var<private> edge_axis: array<i32,12> = array<i32,12>(
     0,
-    1,
     2,
-    3,
-    4,
-    5,
-    6,
-    7,
     0,
-    1,
     2,
-    3
-);
-
-var<private> corner_index_bfrom_edge: array<i32,12> = array<i32,12>(
-
-    1,
+    0,
     2,
-    3,
     0,
-    5,
-    6,
-    7,
-    4,
-    4,
-    5,
-    6,
-    7
+    2,
+    1,
+    1,
+    1,
+    1
 );
 
+// indices are local to the chunk, so the vertex slice can be used as is
+fn edge_vertex(id: vec3<i32>, edge: i32) -> u32 {
+    return edge_vertices.data[to_edge(id + edge_origin[edge], edge_axis[edge])];
+}
+
 
 var<private> tri_table: array<array<i32,16>,256> = array<array<i32,16>,256>(
     array<i32,16>(
@@ -337,13 +475,169 @@ var<private> tri_table: array<array<i32,16>,256> = array<array<i32,16>,256>(
     ),
 );
 
-[[stage(compute), workgroup_size(8, 8, 8)]]
-fn march([[builtin(global_invocation_id)]] id: vec3<u32>) {
-    let id = vec3<i32>(id);
-    if (id.x >= 31 || id.y >= 31 || id.z >= 31) {
-        return;
+var<private> corner_offset: array<vec3<i32>,8> = array<vec3<i32>,8>(
+    vec3<i32>(0, 0, 0),
+    vec3<i32>(1, 0, 0),
+    vec3<i32>(1, 0, 1),
+    vec3<i32>(0, 0, 1),
+    vec3<i32>(0, 1, 0),
+    vec3<i32>(1, 1, 0),
+    vec3<i32>(1, 1, 1),
+    vec3<i32>(0, 1, 1)
+);
+
+// the corners of every face and the edges between them, counter clockwise seen from outside the cell.
+// face_edges[f][k] runs from face_corners[f][k] to face_corners[f][k + 1]
+var<private> face_corners: array<array<i32,4>,6> = array<array<i32,4>,6>(
+    array<i32,4>(0, 3, 7, 4),
+    array<i32,4>(1, 5, 6, 2),
+    array<i32,4>(0, 1, 2, 3),
+    array<i32,4>(4, 7, 6, 5),
+    array<i32,4>(0, 4, 5, 1),
+    array<i32,4>(3, 2, 6, 7)
+);
+
+var<private> face_edges: array<array<i32,4>,6> = array<array<i32,4>,6>(
+    array<i32,4>(3, 11, 7, 8),
+    array<i32,4>(9, 5, 10, 1),
+    array<i32,4>(0, 1, 2, 3),
+    array<i32,4>(7, 6, 5, 4),
+    array<i32,4>(8, 4, 9, 0),
+    array<i32,4>(2, 10, 6, 11)
+);
+
+var<private> corner_values: array<f32,8>;
+
+// the crossing that comes after the one on each edge, going around the loops the surface
+// cuts into the faces of a cell. -1 for edges without a crossing
+var<private> next_crossing: array<i32,12>;
+
+// alternative to tri_table that never leaves holes. every face gets split on its own, ambiguous faces
+// by the asymptotic decider, so the two cells sharing a face always agree. the loops are
+// oriented so that triangle fans over them face outwards. the inside of the cell is not
+// disambiguated, which keeps the mesh manifold but can miss tunnels of the trilinear interpolant
+fn face_loops(id: vec3<i32>) {
+    for (var i = 0; i < 8; i = i + 1) {
+        corner_values[i] = density(id + corner_offset[i]);
     }
+    for (var i = 0; i < 12; i = i + 1) {
+        next_crossing[i] = -1;
+    }
+
+    for (var face = 0; face < 6; face = face + 1) {
+        let c0 = corner_values[face_corners[face][0] ];
+        let c1 = corner_values[face_corners[face][1] ];
+        let c2 = corner_values[face_corners[face][2] ];
+        let c3 = corner_values[face_corners[face][3] ];
+
+        let mask = u32(c0 >= 0.0) | (u32(c1 >= 0.0) << 1u) | (u32(c2 >= 0.0) << 2u) | (u32(c3 >= 0.0) << 3u);
+        let ambiguous = mask == 5u || mask == 10u;
+
+        // the inside corners of an ambiguous face are connected if the saddle of the bilinear
+        // interpolation is inside too. no division, so both cells come to the exact same result
+        var connected = c0 * c2 >= c1 * c3;
+        if (mask == 10u) {
+            connected = c1 * c3 >= c0 * c2;
+        }
+
+        // the surface enters the face's inside where its edge goes from an outside to an inside corner
+        var leaving = 0;
+        for (var k = 0; k < 4; k = k + 1) {
+            if (((mask >> u32(k)) & 1u) == 1u && ((mask >> u32((k + 1) % 4)) & 1u) == 0u) {
+                leaving = k;
+            }
+        }
+
+        for (var k = 0; k < 4; k = k + 1) {
+            if (((mask >> u32(k)) & 1u) == 1u || ((mask >> u32((k + 1) % 4)) & 1u) == 0u) {
+                continue;
+            }
+
+            var leave = leaving;
+            if (ambiguous && connected) {
+                leave = (k + 3) % 4;
+            } else if (ambiguous) {
+                leave = (k + 1) % 4;
+            }
+            next_crossing[face_edges[face][k] ] = face_edges[face][leave];
+        }
+    }
+}
 
+// one bit per face of face_edges every edge lies on
+var<private> edge_faces: array<u32,12> = array<u32,12>(20u, 6u, 36u, 5u, 24u, 10u, 40u, 9u, 17u, 18u, 34u, 33u);
+
+var<private> loop_edges: array<i32,12>;
+
+// follows next_crossing from start into loop_edges, returns the length of the loop
+fn gather_loop(start: i32) -> i32 {
+    var n = 0;
+    var current = start;
+    loop {
+        loop_edges[n] = current;
+        n = n + 1;
+        current = next_crossing[current];
+        if (current == start) {
+            break;
+        }
+    }
+    return n;
+}
+
+// a corner of the gathered loop that can be fanned from. a diagonal between two crossings on the
+// same face could show up in the cell across that face as well, so those are left out.
+// -1 if there is no such corner, the loop then gets a vertex in its middle
+fn fan_apex(n: i32) -> i32 {
+    for (var apex = 0; apex < n; apex = apex + 1) {
+        var free = true;
+        for (var i = 2; i < n - 1; i = i + 1) {
+            if ((edge_faces[loop_edges[apex] ] & edge_faces[loop_edges[(apex + i) % n] ]) != 0u) {
+                free = false;
+            }
+        }
+        if (free) {
+            return apex;
+        }
+    }
+    return -1;
+}
+
+// how many vertices and triangles the loops of face_loops add
+fn loop_counts() -> vec2<u32> {
+    var counts = vec2<u32>(0u, 0u);
+    var visited = 0u;
+    for (var start = 0; start < 12; start = start + 1) {
+        if (next_crossing[start] == -1 || ((visited >> u32(start)) & 1u) == 1u) {
+            continue;
+        }
+
+        let n = gather_loop(start);
+        for (var i = 0; i < n; i = i + 1) {
+            visited = visited | (1u << u32(loop_edges[i]));
+        }
+
+        if (fan_apex(n) >= 0) {
+            counts.y = counts.y + u32(n - 2);
+        } else {
+            counts = counts + vec2<u32>(1u, u32(n));
+        }
+    }
+
+    return counts;
+}
+
+// whether the edge from id along axis crosses the surface
+fn crossing(id: vec3<i32>, axis: i32) -> bool {
+    let other = id + axis_offset[axis];
+    if (any(other > vec3<i32>(dims.axis_size - 1 - padding))) {
+        return false;
+    }
+
+    return (density(id) >= 0.0) != (density(other) >= 0.0);
+}
+
+// the cell with id as its first corner, one bit per corner inside the surface
+fn cube_index(id: vec3<i32>) -> u32 {
     var corners: array<vec4<f32>,8> = array<vec4<f32>,8>(
         to_corner(id),
         to_corner(vec3<i32>(id.x + 1, id.y, id.z)),
@@ -367,33 +661,297 @@ fn march([[builtin(global_invocation_id)]] id: vec3<u32>) {
     index = index | u32(corners[6].w >= 0.0) * (1u << 6u);
     index = index | u32(corners[7].w >= 0.0) * (1u << 7u);
 
+    return index;
+}
+
+fn in_samples(id: vec3<i32>) -> bool {
+    return all(id >= vec3<i32>(padding)) && all(id <= vec3<i32>(dims.axis_size - 1 - padding));
+}
+
+fn in_cells(id: vec3<i32>) -> bool {
+    return all(id >= vec3<i32>(padding)) && all(id < vec3<i32>(dims.axis_size - 1 - padding));
+}
+
+[[stage(compute), workgroup_size(8, 8, 8)]]
+fn edges([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let id = split_batch(id);
+    if (!in_samples(id)) {
+        return;
+    }
+
+    let v1 = to_corner(id);
+    var next = 0u;
 
+    for (var axis = 0; axis < 3; axis = axis + 1) {
+        if (!crossing(id, axis)) {
+            continue;
+        }
+
+        let v2 = to_corner(id + axis_offset[axis]);
+
+        var vert_index: u32;
+        if (dims.ordered == 1u) {
+            vert_index = vert_offsets.data[to_index(id)] + next;
+            next = next + 1u;
+        } else {
+            vert_index = atomicAdd(&global_atomics.chunks[chunk].vert_head, 1u);
+        }
+
+        // keep out of the next chunk's slice
+        if (vert_index >= dims.max_vertices) {
+            continue;
+        }
+        let g = interpolate_gradient(v1, v2);
+        let inside = select(id + axis_offset[axis], id, v1.w >= 0.0);
+        vertices.vertices[u32(chunk) * dims.max_vertices + vert_index] = Vertex(
+            vec4<f32>(interpolate_verts(v1, v2) - f32(padding), length(g)),
+            vec4<f32>(-normalize(g), occlusion(inside)),
+        );
+        edge_vertices.data[to_edge(id, axis)] = vert_index;
+    }
+}
+
+// writes the nth triangle of the cell at id, false once the chunk's slice is full
+fn emit_triangle(id: vec3<i32>, nth: u32, triangle: Triangle) -> bool {
+    var tri_count: u32;
+    if (dims.ordered == 1u) {
+        tri_count = tri_offsets.data[to_index(id)] + nth;
+    } else {
+        tri_count = atomicAdd(&global_atomics.chunks[chunk].tri_head, 1u);
+    }
+    if (tri_count >= dims.max_triangles) {
+        return false;
+    }
+
+    triangles.triangles[u32(chunk) * dims.max_triangles + tri_count] = triangle;
+    return true;
+}
+
+// the nth vertex in the middle of a gathered loop of the cell at id, comes after the crossings of
+// that sample. 0xffffffffu once the chunk's slice is full
+fn emit_center(id: vec3<i32>, nth: u32, n: i32) -> u32 {
+    var vert_index: u32;
+    if (dims.ordered == 1u) {
+        var crossings = 0u;
+        for (var axis = 0; axis < 3; axis = axis + 1) {
+            crossings = crossings + u32(crossing(id, axis));
+        }
+        vert_index = vert_offsets.data[to_index(id)] + crossings + nth;
+    } else {
+        vert_index = atomicAdd(&global_atomics.chunks[chunk].vert_head, 1u);
+    }
+    if (vert_index >= dims.max_vertices) {
+        return 0xffffffffu;
+    }
+
+    var position = vec4<f32>(0.0);
+    var normal = vec4<f32>(0.0);
+    for (var i = 0; i < n; i = i + 1) {
+        let corner = edge_vertex(id, loop_edges[i]);
+        if (corner >= dims.max_vertices) {
+            return 0xffffffffu;
+        }
+        let vertex = vertices.vertices[u32(chunk) * dims.max_vertices + corner];
+        position = position + vertex.position;
+        normal = normal + vertex.normal;
+    }
+
+    vertices.vertices[u32(chunk) * dims.max_vertices + vert_index] = Vertex(
+        position / f32(n),
+        vec4<f32>(normalize(normal.xyz), normal.w / f32(n)),
+    );
+    return vert_index;
+}
+
+[[stage(compute), workgroup_size(8, 8, 8)]]
+fn march([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let id = split_batch(id);
+    if (!in_cells(id)) {
+        return;
+    }
+
+    let index = cube_index(id);
     if (index == 0x00u || index == 0xffu) {
         return;
     }
 
+    if (dims.decider == 1u) {
+        face_loops(id);
+
+        var triangle_count = 0u;
+        var center_count = 0u;
+        var visited = 0u;
+        for (var start = 0; start < 12; start = start + 1) {
+            if (next_crossing[start] == -1 || ((visited >> u32(start)) & 1u) == 1u) {
+                continue;
+            }
+
+            let n = gather_loop(start);
+            for (var i = 0; i < n; i = i + 1) {
+                visited = visited | (1u << u32(loop_edges[i]));
+            }
+
+            let apex = fan_apex(n);
+            if (apex >= 0) {
+                for (var i = 1; i < n - 1; i = i + 1) {
+                    let triangle = Triangle(
+                        edge_vertex(id, loop_edges[apex]),
+                        edge_vertex(id, loop_edges[(apex + i) % n]),
+                        edge_vertex(id, loop_edges[(apex + i + 1) % n]),
+                    );
+                    if (!emit_triangle(id, triangle_count, triangle)) {
+                        return;
+                    }
+                    triangle_count = triangle_count + 1u;
+                }
+                continue;
+            }
+
+            let center = emit_center(id, center_count, n);
+            if (center == 0xffffffffu) {
+                return;
+            }
+            center_count = center_count + 1u;
+
+            for (var i = 0; i < n; i = i + 1) {
+                let triangle = Triangle(
+                    center,
+                    edge_vertex(id, loop_edges[i]),
+                    edge_vertex(id, loop_edges[(i + 1) % n]),
+                );
+                if (!emit_triangle(id, triangle_count, triangle)) {
+                    return;
+                }
+                triangle_count = triangle_count + 1u;
+            }
+        }
+        return;
+    }
+
     for (var i = 0u; i < 15u; i = i+3u) {
         if (tri_table[index][i] == -1) {
             break;
         }
-        
-        var tri_count = atomicAdd(&global_atomics.tri_head, 1u);
 
-        let a0 = corner_index_afrom_edge[tri_table[index][i] ];
-        let b0 = corner_index_bfrom_edge[tri_table[index][i] ];
+        let triangle = Triangle(
+            edge_vertex(id, tri_table[index][i]),
+            edge_vertex(id, tri_table[index][i+1u]),
+            edge_vertex(id, tri_table[index][i+2u]),
+        );
+        if (!emit_triangle(id, i / 3u, triangle)) {
+            break;
+        }
+    }
+}
+
+// first step of the ordered mode, how many vertices and triangles every sample adds
+[[stage(compute), workgroup_size(8, 8, 8)]]
+fn count([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    let id = split_batch(id);
+    if (any(id >= vec3<i32>(dims.axis_size))) {
+        return;
+    }
+
+    var verts = 0u;
+    if (in_samples(id)) {
+        for (var axis = 0; axis < 3; axis = axis + 1) {
+            verts = verts + u32(crossing(id, axis));
+        }
+    }
+
+    var tris = 0u;
+    if (in_cells(id) && dims.decider == 1u) {
+        face_loops(id);
+        let counts = loop_counts();
+        verts = verts + counts.x;
+        tris = counts.y;
+    } else if (in_cells(id)) {
+        let index = cube_index(id);
+        for (var i = 0u; i < 15u; i = i+3u) {
+            if (tri_table[index][i] == -1) {
+                break;
+            }
+            tris = tris + 1u;
+        }
+    }
+
+    vert_offsets.data[to_index(id)] = verts;
+    tri_offsets.data[to_index(id)] = tris;
+}
+
+var<workgroup> vert_sums: array<u32, 256>;
+var<workgroup> tri_sums: array<u32, 256>;
 
-        let a1 = corner_index_afrom_edge[tri_table[index][i+1u] ];
-        let b1 = corner_index_bfrom_edge[tri_table[index][i+1u] ];
+// exclusive prefix sum over the counts of one chunk per workgroup. every invocation sums up a
+// block of samples, the block sums get scanned in shared memory and then spread back over the blocks.
+// the totals end up in the atomics, just like after appending
+[[stage(compute), workgroup_size(256)]]
+fn scan(
+    [[builtin(local_invocation_id)]] local: vec3<u32>,
+    [[builtin(workgroup_id)]] group: vec3<u32>,
+) {
+    chunk = i32(group.z);
+    let total = u32(dims.axis_size * dims.axis_size * dims.axis_size);
+    let offset = u32(chunk) * total;
+    let per_thread = (total + 255u) / 256u;
+    let first = min(local.x * per_thread, total);
+    let last = min(first + per_thread, total);
 
-        let a2 = corner_index_afrom_edge[tri_table[index][i+2u] ];
-        let b2 = corner_index_bfrom_edge[tri_table[index][i+2u] ];
+    var vert_sum = 0u;
+    var tri_sum = 0u;
+    for (var i = first; i < last; i = i + 1u) {
+        vert_sum = vert_sum + vert_offsets.data[offset + i];
+        tri_sum = tri_sum + tri_offsets.data[offset + i];
+    }
+    vert_sums[local.x] = vert_sum;
+    tri_sums[local.x] = tri_sum;
+    workgroupBarrier();
 
-        var triangle: Triangle = Triangle(
-            vec4<f32>(interpolate_verts(corners[a0], corners[b0]), f32(id.x) ), 
-            vec4<f32>(interpolate_verts(corners[a1], corners[b1]), f32(index) + f32(i) /10.0 ), 
-            vec4<f32>(interpolate_verts(corners[a2], corners[b2]), f32(id.z) ),);
-        
-        
-        triangles.triangles[tri_count] = triangle;
+    // inclusive scan of the block sums
+    for (var stride = 1u; stride < 256u; stride = stride * 2u) {
+        var verts = 0u;
+        var tris = 0u;
+        if (local.x >= stride) {
+            verts = vert_sums[local.x - stride];
+            tris = tri_sums[local.x - stride];
+        }
+        workgroupBarrier();
+        vert_sums[local.x] = vert_sums[local.x] + verts;
+        tri_sums[local.x] = tri_sums[local.x] + tris;
+        workgroupBarrier();
     }
-}
\ No newline at end of file
+
+    var vert_head = vert_sums[local.x] - vert_sum;
+    var tri_head = tri_sums[local.x] - tri_sum;
+    for (var i = first; i < last; i = i + 1u) {
+        let verts = vert_offsets.data[offset + i];
+        let tris = tri_offsets.data[offset + i];
+        vert_offsets.data[offset + i] = vert_head;
+        tri_offsets.data[offset + i] = tri_head;
+        vert_head = vert_head + verts;
+        tri_head = tri_head + tris;
+    }
+
+    if (local.x == 255u) {
+        atomicStore(&global_atomics.chunks[chunk].vert_head, vert_sums[255]);
+        atomicStore(&global_atomics.chunks[chunk].tri_head, tri_sums[255]);
+    }
+}
+
+// turns the triangle count of every chunk in the batch into draw arguments,
+// so the triangle slice can be drawn as an index buffer without reading the count back
+[[stage(compute), workgroup_size(64)]]
+fn indirect([[builtin(global_invocation_id)]] id: vec3<u32>) {
+    if (id.x >= arrayLength(&draw_args.args)) {
+        return;
+    }
+
+    // an overflowed chunk misses vertices or triangles, draw nothing until it got meshed again
+    let vert_head = atomicLoad(&global_atomics.chunks[id.x].vert_head);
+    var tri_head = atomicLoad(&global_atomics.chunks[id.x].tri_head);
+    if (vert_head > dims.max_vertices || tri_head > dims.max_triangles) {
+        tri_head = 0u;
+    }
+
+    draw_args.args[id.x] = DrawArgs(tri_head * 3u, 1u, 0u, 0, 0u);
+}
@@ -1,34 +1,65 @@
+// the fractal terrain is built from, see TerrainParams
 struct Params {
     freq: f32;
     amp: f32;
     lacunarity: f32;
     gain: f32;
     octaves: u32;
+    // the u64 seed split into its low and high half
+    seed_lo: u32;
+    seed_hi: u32;
+    // which TerrainPreset and its two parameters, in the order the variant declares them
+    preset: u32;
+    preset_a: f32;
+    preset_b: f32;
+    // 1 when TerrainParams::caves is set, see Caves for the rest
+    caves: u32;
+    tunnel_freq: f32;
+    tunnel_radius: f32;
+    tunnel_top: f32;
+    tunnel_bottom: f32;
+    cheese_freq: f32;
+    cheese_threshold: f32;
+    cheese_top: f32;
+    cheese_bottom: f32;
+    cave_fade: f32;
 };
 
-struct Position {
-    pos: vec4<f32>;
+// chunk origin in xyz, distance between samples in w, for every chunk of the batch
+struct Positions {
+    data: [[stride(16)]] array<vec4<f32>>;
 };
 
 struct Values {
     data : [[stride(4)]] array<f32>;
 };
 
+// samples along one axis of the chunk, padding included
+struct Dims {
+    axis_size: u32;
+};
 
-// [[group(0), binding(0)]]
-// var<uniform> params: Params;
 
 [[group(0), binding(0)]]
-var<uniform> pos: Position;
+var<storage, read> positions: Positions;
 
 [[group(0), binding(1)]]
 var<storage, read_write> values: Values;
 
+[[group(0), binding(2)]]
+var<uniform> dims: Dims;
+
+[[group(0), binding(3)]]
+var<uniform> params: Params;
+
+// matches the padding in marchig_cubes.wgsl, the first sample sits one step before the chunk origin
+let padding: i32 = 1;
+
 let one: vec4<f32> = vec4<f32>(1.0, 1.0, 1.0, 1.0);
 let zero: vec3<f32> = vec3<f32>(0.0, 0.0, 0.0);
 
 fn to_index(pos: vec3<u32>) -> i32 {
-    return i32(pos.x << 0u | pos.y << 10u | pos.z << 5u);
+    return i32(pos.x + pos.z * dims.axis_size + pos.y * dims.axis_size * dims.axis_size);
 }
 
 fn permute(x: vec4<f32>) -> vec4<f32> {
@@ -119,10 +150,32 @@ fn noise(v: vec3<f32>) -> f32{
 }
 
 
-fn hash(p: vec2<f32>) -> vec2<f32> // replace this by something better
+// pcg3d from "Hash Functions for GPU Rendering" (Jarzynski, Olano). integer math only, unlike a
+// sin based hash it gives the same bits on every gpu, so a seed always makes the same world
+fn pcg3d(v: vec3<u32>) -> vec3<u32> {
+    var v = v * 1664525u + 1013904223u;
+    v.x = v.x + v.y * v.z;
+    v.y = v.y + v.z * v.x;
+    v.z = v.z + v.x * v.y;
+    v = v ^ (v >> vec3<u32>(16u));
+    v.x = v.x + v.y * v.z;
+    v.y = v.y + v.z * v.x;
+    v.z = v.z + v.x * v.y;
+    return v;
+}
+
+// random vector in [-0.5, 0.5) for a lattice point c, which holds whole numbers
+fn random3(c: vec3<f32>) -> vec3<f32>
+{
+    let seed = vec3<u32>(params.seed_lo, params.seed_hi, params.seed_lo ^ params.seed_hi);
+    let h = pcg3d(bitcast<vec3<u32>>(vec3<i32>(c)) ^ pcg3d(seed));
+    // the top 24 bits fit a f32 exactly
+    return vec3<f32>(h >> vec3<u32>(8u)) / 16777216.0 - 0.5;
+}
+
+fn hash(p: vec2<f32>) -> vec2<f32>
 {
-    let p2 = vec2<f32>( dot(p,vec2<f32>(127.1,311.7)), dot(p,vec2<f32>(269.5,183.3)) );
-    return -1.0 + 2.0*fract(sin(p2)*43758.5453123);
+    return 2.0 * random3(vec3<f32>(p, 0.0)).xy;
 }
 
 fn simplex2d(p: vec2<f32>) -> f32
@@ -140,19 +193,7 @@ fn simplex2d(p: vec2<f32>) -> f32
 }
 
 
-// Simplex Noise 3D: https://www.shadertoy.com/view/XsX3zB
-
-fn random3(c: vec3<f32>) -> vec3<f32>
-{
-    var j = 4096.0*sin(dot(c,vec3<f32>(17.0, 59.4, 15.0)));
-    var r = vec3<f32>(0.);
-    r.z = fract(512.0*j);
-    j = j * .125;
-    r.x = fract(512.0*j);
-    j = j * .125;
-    r.y = fract(512.0*j);
-    return r - 0.5;
-}
+// Simplex Noise 3D: https://www.shadertoy.com/view/XsX3zB, with random3 above as the gradient hash
 
 let F3 = 0.3333333;
 let G3 = 0.1666667;
@@ -217,21 +258,153 @@ fn simplex3d_fractal(m: vec3<f32>) -> f32
             +0.0666667*simplex3d(8.0 * m );
 }
 
+// the building blocks of the density graph, see noise/graph.rs. noise/simplex.rs has the same on the cpu
+
+fn fbm(p: vec3<f32>, freq: f32, amp: f32, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+    var freq = freq;
+    var amp = amp;
+    var sum = 0.0;
+    for (var i = 0u; i < octaves; i = i + 1u) {
+        sum = sum + simplex3d(p * freq) * amp;
+        amp = amp * gain;
+        freq = freq * lacunarity;
+    }
+    return sum;
+}
+
+// ridged multifractal, sharp crests where the noise crosses zero. every octave is weighted by the
+// one before, so the detail gathers on the ridges and the valleys stay smooth
+fn ridged(p: vec3<f32>, freq: f32, amp: f32, octaves: u32, lacunarity: f32, gain: f32, offset: f32) -> f32 {
+    var freq = freq;
+    var amp = amp;
+    var weight = 1.0;
+    var sum = 0.0;
+    for (var i = 0u; i < octaves; i = i + 1u) {
+        let n = offset - abs(simplex3d(p * freq));
+        let n = n * n * weight;
+        weight = clamp(n * 2.0, 0.0, 1.0);
+        sum = sum + n * amp;
+        amp = amp * gain;
+        freq = freq * lacunarity;
+    }
+    return sum;
+}
+
+// puffy rounded hills with creases in between
+fn billow(p: vec3<f32>, freq: f32, amp: f32, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+    var freq = freq;
+    var amp = amp;
+    var sum = 0.0;
+    for (var i = 0u; i < octaves; i = i + 1u) {
+        sum = sum + abs(simplex3d(p * freq)) * amp;
+        amp = amp * gain;
+        freq = freq * lacunarity;
+    }
+    return sum;
+}
+
+fn warp(p: vec3<f32>, freq: f32, strength: f32) -> vec3<f32> {
+    let q = p * freq;
+    return p + vec3<f32>(
+        simplex3d(q),
+        simplex3d(q + vec3<f32>(5.2, 1.3, 2.8)),
+        simplex3d(q + vec3<f32>(1.7, 9.2, 3.4))
+    ) * strength;
+}
+
+// solid inside, so the negated distance to the box
+fn cuboid(p: vec3<f32>, center: vec3<f32>, half_size: vec3<f32>) -> f32 {
+    let q = abs(p - center) - half_size;
+    return -(length(max(q, vec3<f32>(0.0))) + min(max(q.x, max(q.y, q.z)), 0.0));
+}
+
+// union of two densities with a rounded seam k wide, a smooth max since solid is positive
+fn smooth_union(a: f32, b: f32, k: f32) -> f32 {
+    let h = clamp(0.5 + 0.5 * (a - b) / k, 0.0, 1.0);
+    return mix(b, a, h) + k * h * (1.0 - h);
+}
+
+// flat steps height apart with steep risers in between
+fn terrace(v: f32, height: f32) -> f32 {
+    let t = v / height;
+    let f = floor(t);
+    let r = t - f;
+    let r = r * r;
+    let r = r * r;
+    return (f + r * r) * height;
+}
+
+// DensityNode::Terrain, the TerrainPreset picked in params
+fn preset_terrain(p: vec3<f32>) -> f32 {
+    if (params.preset == 1u) {
+        return ridged(p, params.freq, params.amp, params.octaves, params.lacunarity, params.gain, params.preset_a);
+    }
+    if (params.preset == 2u) {
+        let warped = warp(p, params.preset_a, params.preset_b);
+        return fbm(warped, params.freq, params.amp, params.octaves, params.lacunarity, params.gain);
+    }
+    if (params.preset == 3u) {
+        return billow(p, params.freq, params.amp, params.octaves, params.lacunarity, params.gain);
+    }
+    let height = fbm(p, params.freq, params.amp, params.octaves, params.lacunarity, params.gain);
+    if (params.preset == 4u) {
+        return terrace(height, params.preset_a);
+    }
+    return height;
+}
+
+// scales the cave noise up to about the slope of the terrain
+let cave_scale: f32 = 8.0;
+// how far below 0 the cave noise still carves, the shell around every cave
+let cave_shell: f32 = 0.25;
+
+// 1 between top and bottom, fading to 0 over cave_fade outside
+fn depth_band(y: f32, top: f32, bottom: f32) -> f32 {
+    return clamp((top - y) / params.cave_fade, 0.0, 1.0) * clamp((y - bottom) / params.cave_fade, 0.0, 1.0);
+}
+
+// carves worm tunnels and cheese caves out of density. both are positive inside the cave, the
+// tunnels run where two noise fields are close to zero at once, the cheese where one is high.
+// density is left alone outside the caves and their shells, it's only capped close to them
+fn caves(p: vec3<f32>, density: f32) -> f32 {
+    if (params.caves == 0u) {
+        return density;
+    }
+
+    let q = p * params.tunnel_freq;
+    let worm = vec2<f32>(simplex3d(q), simplex3d(q + vec3<f32>(31.4, 17.1, 5.9)));
+    let tunnel = 1.0 - length(worm) / params.tunnel_radius;
+    let tunnel = mix(-1.0, tunnel, depth_band(p.y, params.tunnel_top, params.tunnel_bottom));
+
+    let n = simplex3d(p * params.cheese_freq + vec3<f32>(-13.7, 7.3, 23.1));
+    let cheese = (n - params.cheese_threshold) / (1.0 - params.cheese_threshold);
+    let cheese = mix(-1.0, cheese, depth_band(p.y, params.cheese_top, params.cheese_bottom));
+
+    let cave = max(tunnel, cheese);
+    if (cave <= -cave_shell) {
+        return density;
+    }
+    return min(density, -cave * cave_scale / (1.0 + cave / cave_shell));
+}
+
+// OpenSimplex puts the compiled DensityGraph, fn terrain(pos: vec3<f32>) -> f32, in place of the next line
+// #terrain
+
 [[stage(compute), workgroup_size(8, 8, 8)]]
 fn main([[builtin(global_invocation_id)]] id: vec3<u32>) {
-    let pos: vec3<i32> = vec3<i32>(pos.pos.xyz) + vec3<i32>(id);
-    
-    var amp = 140.0;
-    var freq = 0.003;
+    // the chunks of a batch are stacked along z, each one workgroup aligned
+    let stride = (dims.axis_size + 7u) / 8u * 8u;
+    let chunk = id.z / stride;
+    let id = vec3<u32>(id.x, id.y, id.z % stride);
 
-    var density: f32 = f32(-pos.y);
-    // density = 50.0 - distance(vec3<f32>(pos), vec3<f32>(0.0, -50.0, 0.0));
+    if (any(id >= vec3<u32>(dims.axis_size))) {
+        return;
+    }
 
-    for (var i = 0; i < 10; i = i+1) {
-        density = density + simplex3d(vec3<f32>(pos) * freq) * amp;
-        amp = amp * 0.5;
-        freq = freq * 2.0;
-    }    
+    let origin = positions.data[chunk];
+    let pos: vec3<f32> = origin.xyz + (vec3<f32>(id) - f32(padding)) * origin.w;
+    let density = caves(pos, terrain(pos));
 
-    values.data[to_index(id)] = density;
+    let chunk_offset = i32(chunk * dims.axis_size * dims.axis_size * dims.axis_size);
+    values.data[chunk_offset + to_index(id)] = density;
 }
@@ -17,8 +17,8 @@ fn main() {
         .add_plugins(DefaultPlugins)
         .add_plugin(WireframePlugin)
         .add_plugin(FlyCameraPlugin)
-        .add_plugin(ChunkPlugin)
-        .add_plugin(NoisePlugin)
+        .add_plugin(ChunkPlugin::default())
+        .add_plugin(NoisePlugin::default())
         .add_startup_system(setup)
         .add_system(cursor_grab_system)
         // .add_system(terrain_edit)
@@ -21,6 +21,14 @@ struct VertexOutput {
     color: Color
 }
 
+// length of the density gradient at the vertex, small where the field only barely crosses the surface
+pub const ATTRIBUTE_GRADIENT: MeshVertexAttribute =
+    MeshVertexAttribute::new("Vertex_Gradient", 415130247, VertexFormat::Float32);
+
+// 1 where the surface is open, going down to 0 in creases and holes
+pub const ATTRIBUTE_OCCLUSION: MeshVertexAttribute =
+    MeshVertexAttribute::new("Vertex_Occlusion", 415130248, VertexFormat::Float32);
+
 #[derive(Debug, Clone, TypeUuid)]
 #[uuid = "f690fdae-d598-45ab-8225-97e2a3f056e0"]
 pub struct ChunkMaterial;
@@ -86,6 +94,8 @@ impl Material for ChunkMaterial {
         let vertex_layout = layout.get_layout(&[
             Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
             Mesh::ATTRIBUTE_NORMAL.at_shader_location(1),
+            ATTRIBUTE_GRADIENT.at_shader_location(2),
+            ATTRIBUTE_OCCLUSION.at_shader_location(3),
         ])?;
         descriptor.vertex.buffers = vec![vertex_layout];
         Ok(())
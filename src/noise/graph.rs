@@ -0,0 +1,206 @@
+use std::fmt::Write;
+
+use bevy::prelude::*;
+
+use crate::world::chunk::{ChunkDims, PADDING};
+
+use super::{simplex::{self, Fractal, Simplex}, TerrainParams};
+
+// the density of the terrain as a tree of nodes, compiled into the noise shader and evaluated on
+// the cpu alike. positive is solid, so unions take the larger value
+#[derive(Clone, Debug, PartialEq)]
+pub enum DensityNode {
+    Constant(f32),
+    // ground at the given height, solid below
+    Height(f32),
+    Sphere { center: Vec3, radius: f32 },
+    Cuboid { center: Vec3, half_size: Vec3 },
+    Noise { freq: f32, amp: f32 },
+    Fbm(Fractal),
+    Ridged(Fractal),
+    // the TerrainPreset of TerrainParams, read from the uniform so it can change without a new pipeline
+    Terrain,
+    // samples node at a position pushed around by noise
+    Warp { freq: f32, strength: f32, node: Box<DensityNode> },
+    Add(Box<DensityNode>, Box<DensityNode>),
+    Scale(f32, Box<DensityNode>),
+    Union(Box<DensityNode>, Box<DensityNode>),
+    Intersect(Box<DensityNode>, Box<DensityNode>),
+    // carves the second node out of the first
+    Subtract(Box<DensityNode>, Box<DensityNode>),
+    SmoothUnion { k: f32, a: Box<DensityNode>, b: Box<DensityNode> },
+    Clamp { min: f32, max: f32, node: Box<DensityNode> },
+    Terrace { height: f32, node: Box<DensityNode> },
+}
+
+impl std::ops::Add for DensityNode {
+    type Output = Self;
+
+    fn add(self, other: DensityNode) -> Self {
+        Self::Add(Box::new(self), Box::new(other))
+    }
+}
+
+impl DensityNode {
+    pub fn scale(self, factor: f32) -> Self {
+        Self::Scale(factor, Box::new(self))
+    }
+
+    pub fn union(self, other: DensityNode) -> Self {
+        Self::Union(Box::new(self), Box::new(other))
+    }
+
+    pub fn intersect(self, other: DensityNode) -> Self {
+        Self::Intersect(Box::new(self), Box::new(other))
+    }
+
+    pub fn subtract(self, other: DensityNode) -> Self {
+        Self::Subtract(Box::new(self), Box::new(other))
+    }
+
+    pub fn smooth_union(self, other: DensityNode, k: f32) -> Self {
+        Self::SmoothUnion { k, a: Box::new(self), b: Box::new(other) }
+    }
+
+    pub fn clamp(self, min: f32, max: f32) -> Self {
+        Self::Clamp { min, max, node: Box::new(self) }
+    }
+
+    pub fn terrace(self, height: f32) -> Self {
+        Self::Terrace { height, node: Box::new(self) }
+    }
+
+    pub fn warp(self, freq: f32, strength: f32) -> Self {
+        Self::Warp { freq, strength, node: Box::new(self) }
+    }
+
+    pub fn eval(&self, pos: Vec3, noise: &Simplex, params: &TerrainParams) -> f32 {
+        let eval = |node: &DensityNode| node.eval(pos, noise, params);
+
+        match self {
+            Self::Constant(value) => *value,
+            Self::Height(height) => height - pos.y,
+            Self::Sphere { center, radius } => radius - pos.distance(*center),
+            Self::Cuboid { center, half_size } => {
+                let q = (pos - *center).abs() - *half_size;
+                -(q.max(Vec3::ZERO).length() + q.max_element().min(0.0))
+            }
+            Self::Noise { freq, amp } => noise.simplex3d(pos * *freq) * amp,
+            Self::Fbm(x) => noise.fbm(pos, x),
+            Self::Ridged(x) => noise.ridged(pos, x, 1.0),
+            Self::Terrain => params.terrain(pos, noise),
+            Self::Warp { freq, strength, node } => node.eval(noise.warp(pos, *freq, *strength), noise, params),
+            Self::Add(a, b) => eval(a) + eval(b),
+            Self::Scale(factor, node) => eval(node) * factor,
+            Self::Union(a, b) => eval(a).max(eval(b)),
+            Self::Intersect(a, b) => eval(a).min(eval(b)),
+            Self::Subtract(a, b) => eval(a).min(-eval(b)),
+            Self::SmoothUnion { k, a, b } => {
+                let (a, b) = (eval(a), eval(b));
+                let h = (0.5 + 0.5 * (a - b) / k).clamp(0.0, 1.0);
+                b + (a - b) * h + k * h * (1.0 - h)
+            }
+            Self::Clamp { min, max, node } => eval(node).clamp(*min, *max),
+            Self::Terrace { height, node } => simplex::terrace(eval(node), *height),
+        }
+    }
+
+    // one let per node, returns the name holding the node's value
+    fn compile(&self, pos: &str, out: &mut Wgsl) -> String {
+        let expr = match self {
+            Self::Constant(value) => float(*value),
+            Self::Height(height) => format!("{} - {}.y", float(*height), pos),
+            Self::Sphere { center, radius } => format!("{} - distance({}, {})", float(*radius), pos, vec3(*center)),
+            Self::Cuboid { center, half_size } => format!("cuboid({}, {}, {})", pos, vec3(*center), vec3(*half_size)),
+            Self::Noise { freq, amp } => format!("simplex3d({} * {}) * {}", pos, float(*freq), float(*amp)),
+            Self::Fbm(x) => format!("fbm({}, {})", pos, fractal(x)),
+            Self::Ridged(x) => format!("ridged({}, {}, 1.0)", pos, fractal(x)),
+            Self::Terrain => format!("preset_terrain({})", pos),
+            Self::Warp { freq, strength, node } => {
+                let warped = out.push(format!("warp({}, {}, {})", pos, float(*freq), float(*strength)));
+                return node.compile(&warped, out);
+            }
+            Self::Add(a, b) => format!("{} + {}", a.compile(pos, out), b.compile(pos, out)),
+            Self::Scale(factor, node) => format!("{} * {}", node.compile(pos, out), float(*factor)),
+            Self::Union(a, b) => format!("max({}, {})", a.compile(pos, out), b.compile(pos, out)),
+            Self::Intersect(a, b) => format!("min({}, {})", a.compile(pos, out), b.compile(pos, out)),
+            Self::Subtract(a, b) => format!("min({}, -{})", a.compile(pos, out), b.compile(pos, out)),
+            Self::SmoothUnion { k, a, b } => format!("smooth_union({}, {}, {})", a.compile(pos, out), b.compile(pos, out), float(*k)),
+            Self::Clamp { min, max, node } => format!("clamp({}, {}, {})", node.compile(pos, out), float(*min), float(*max)),
+            Self::Terrace { height, node } => format!("terrace({}, {})", node.compile(pos, out), float(*height)),
+        };
+
+        out.push(expr)
+    }
+}
+
+// the resource OpenSimplex builds its pipeline from. the default is the heightfield noise.wgsl
+// always had: ground at zero plus the TerrainParams terrain
+#[derive(Clone, Debug, PartialEq)]
+pub struct DensityGraph(pub DensityNode);
+
+impl Default for DensityGraph {
+    fn default() -> Self {
+        Self(DensityNode::Height(0.0) + DensityNode::Terrain)
+    }
+}
+
+impl DensityGraph {
+    // with the caves of params carved out, like main in noise.wgsl does
+    pub fn eval(&self, pos: Vec3, noise: &Simplex, params: &TerrainParams) -> f32 {
+        let density = self.0.eval(pos, noise, params);
+        match params.caves {
+            Some(caves) => caves.carve(pos, noise, density),
+            None => density,
+        }
+    }
+
+    // the samples of a chunk where noise.wgsl puts them, padding included
+    pub fn eval_chunk(&self, origin: Vec3, scale: f32, dims: ChunkDims, params: &TerrainParams) -> Vec<f32> {
+        let noise = params.simplex();
+        (0..dims.buffer_size())
+            .map(|i| self.eval(origin + (dims.from_index(i).as_vec3() - PADDING as f32) * scale, &noise, params))
+            .collect()
+    }
+
+    // fn terrain(pos: vec3<f32>) -> f32, on top of the functions in noise.wgsl
+    pub fn to_wgsl(&self) -> String {
+        let mut out = Wgsl::default();
+        let result = self.0.compile("pos", &mut out);
+
+        format!("fn terrain(pos: vec3<f32>) -> f32 {{\n{}    return {};\n}}\n", out.body, result)
+    }
+}
+
+#[derive(Default)]
+struct Wgsl {
+    body: String,
+    next: usize,
+}
+
+impl Wgsl {
+    fn push(&mut self, expr: String) -> String {
+        let name = format!("n{}", self.next);
+        self.next += 1;
+        writeln!(self.body, "    let {} = {};", name, expr).unwrap();
+        name
+    }
+}
+
+// wgsl wants a point or an exponent in every float literal
+fn float(x: f32) -> String {
+    let literal = format!("{:?}", x);
+    let literal = match literal.find('e') {
+        Some(i) if !literal.contains('.') => format!("{}.0{}", &literal[..i], &literal[i..]),
+        _ => literal,
+    };
+    if x.is_sign_negative() { format!("({})", literal) } else { literal }
+}
+
+fn vec3(v: Vec3) -> String {
+    format!("vec3<f32>({}, {}, {})", float(v.x), float(v.y), float(v.z))
+}
+
+fn fractal(x: &Fractal) -> String {
+    format!("{}, {}, {}u, {}, {}", float(x.freq), float(x.amp), x.octaves, float(x.lacunarity), float(x.gain))
+}
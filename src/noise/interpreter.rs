@@ -0,0 +1,551 @@
+// runs a compute shader on the cpu, straight from the naga ir. only what noise.wgsl and the
+// compiled density graphs use is there, anything else panics. lets the tests check the shader
+// against the cpu port without a wgpu adapter
+
+use naga::{
+    ArraySize, BinaryOperator, Binding, BuiltIn, ConstantInner, Expression, Function, Handle,
+    MathFunction, Module, RelationalFunction, ScalarKind, ScalarValue, Statement, TypeInner,
+    UnaryOperator,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    F32(f32),
+    U32(u32),
+    I32(i32),
+    Bool(bool),
+    // vectors, matrices as their columns, structs and arrays
+    Composite(Vec<Value>),
+    Pointer(Root, Vec<usize>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Root {
+    Global(usize),
+    Local(usize),
+}
+
+enum Flow {
+    Next,
+    Break,
+    Continue,
+    Return(Option<Value>),
+}
+
+struct Frame<'a> {
+    function: &'a Function,
+    arguments: Vec<Value>,
+    locals: Vec<Value>,
+    expressions: Vec<Option<Value>>,
+}
+
+pub struct Interpreter<'a> {
+    module: &'a Module,
+    globals: Vec<Value>,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new(module: &'a Module) -> Self {
+        let globals = module.global_variables.iter()
+            .map(|(_, global)| global.init.map_or(Value::Composite(Vec::new()), |init| constant(module, init)))
+            .collect();
+        Self { module, globals }
+    }
+
+    // the contents of the buffer at group and binding, laid out like wgpu would
+    pub fn bind(&mut self, group: u32, binding: u32, words: &[u32]) {
+        let (index, ty) = self.global(group, binding);
+        self.globals[index] = decode(self.module, ty, words, 0);
+    }
+
+    pub fn read(&self, group: u32, binding: u32, words: &mut [u32]) {
+        let (index, ty) = self.global(group, binding);
+        encode(self.module, ty, &self.globals[index], words, 0);
+    }
+
+    // one invocation of the entry point per global invocation id, in order
+    pub fn dispatch(&mut self, entry_point: &str, ids: impl IntoIterator<Item = [u32; 3]>) {
+        let module = self.module;
+        let function = &module.entry_points.iter()
+            .find(|entry| entry.name == entry_point)
+            .unwrap_or_else(|| panic!("no entry point {}", entry_point))
+            .function;
+
+        for id in ids {
+            let arguments = function.arguments.iter()
+                .map(|argument| match argument.binding {
+                    Some(Binding::BuiltIn(BuiltIn::GlobalInvocationId)) => Value::Composite(id.iter().map(|x| Value::U32(*x)).collect()),
+                    ref binding => panic!("unsupported entry point argument {:?}", binding),
+                })
+                .collect();
+            self.call(function, arguments);
+        }
+    }
+
+    fn global(&self, group: u32, binding: u32) -> (usize, Handle<naga::Type>) {
+        self.module.global_variables.iter()
+            .find(|(_, global)| matches!(global.binding, Some(ref b) if b.group == group && b.binding == binding))
+            .map(|(handle, global)| (handle.index(), global.ty))
+            .unwrap_or_else(|| panic!("nothing bound at group {} binding {}", group, binding))
+    }
+
+    fn call(&mut self, function: &'a Function, arguments: Vec<Value>) -> Option<Value> {
+        let locals = function.local_variables.iter()
+            .map(|(_, local)| local.init.map_or(Value::Composite(Vec::new()), |init| constant(self.module, init)))
+            .collect();
+        let mut frame = Frame { function, arguments, locals, expressions: vec![None; function.expressions.len()] };
+
+        match self.block(&mut frame, &function.body) {
+            Flow::Return(value) => value,
+            _ => None,
+        }
+    }
+
+    fn block(&mut self, frame: &mut Frame<'a>, block: &[Statement]) -> Flow {
+        for statement in block {
+            let flow = match *statement {
+                Statement::Emit(ref range) => {
+                    for handle in range.clone() {
+                        let value = self.expression(frame, handle);
+                        frame.expressions[handle.index()] = Some(value);
+                    }
+                    Flow::Next
+                }
+                Statement::Block(ref block) => self.block(frame, block),
+                Statement::If { condition, ref accept, ref reject } => {
+                    match self.eval(frame, condition) {
+                        Value::Bool(true) => self.block(frame, accept),
+                        Value::Bool(false) => self.block(frame, reject),
+                        value => panic!("if on {:?}", value),
+                    }
+                }
+                Statement::Loop { ref body, ref continuing } => loop {
+                    match self.block(frame, body) {
+                        Flow::Break => break Flow::Next,
+                        Flow::Return(value) => break Flow::Return(value),
+                        Flow::Next | Flow::Continue => {}
+                    }
+                    if let Flow::Return(value) = self.block(frame, continuing) {
+                        break Flow::Return(value);
+                    }
+                },
+                Statement::Break => Flow::Break,
+                Statement::Continue => Flow::Continue,
+                Statement::Return { value } => Flow::Return(value.map(|value| self.eval(frame, value))),
+                Statement::Store { pointer, value } => {
+                    let value = self.eval(frame, value);
+                    match self.eval(frame, pointer) {
+                        Value::Pointer(root, path) => *self.place(frame, root, &path) = value,
+                        pointer => panic!("store to {:?}", pointer),
+                    }
+                    Flow::Next
+                }
+                Statement::Call { function, ref arguments, result } => {
+                    let arguments = arguments.iter().map(|argument| self.eval(frame, *argument)).collect();
+                    let module = self.module;
+                    let value = self.call(&module.functions[function], arguments);
+                    if let Some(result) = result {
+                        frame.expressions[result.index()] = value;
+                    }
+                    Flow::Next
+                }
+                Statement::Barrier(_) => Flow::Next,
+                ref statement => panic!("unsupported statement {:?}", statement),
+            };
+
+            if !matches!(flow, Flow::Next) {
+                return flow;
+            }
+        }
+        Flow::Next
+    }
+
+    fn place<'f>(&'f mut self, frame: &'f mut Frame<'a>, root: Root, path: &[usize]) -> &'f mut Value {
+        let mut value = match root {
+            Root::Global(index) => &mut self.globals[index],
+            Root::Local(index) => &mut frame.locals[index],
+        };
+        for index in path {
+            value = match value {
+                Value::Composite(components) => &mut components[*index],
+                value => panic!("index {} into {:?}", index, value),
+            };
+        }
+        value
+    }
+
+    // the value of an emitted expression, or of one that doesn't need emitting
+    fn eval(&mut self, frame: &mut Frame<'a>, handle: Handle<Expression>) -> Value {
+        match frame.expressions[handle.index()] {
+            Some(ref value) => value.clone(),
+            None => self.expression(frame, handle),
+        }
+    }
+
+    fn expression(&mut self, frame: &mut Frame<'a>, handle: Handle<Expression>) -> Value {
+        match frame.function.expressions[handle] {
+            Expression::Access { base, index } => {
+                let index = match self.eval(frame, index) {
+                    Value::U32(x) => x as usize,
+                    Value::I32(x) => x as usize,
+                    value => panic!("index with {:?}", value),
+                };
+                access(self.eval(frame, base), index)
+            }
+            Expression::AccessIndex { base, index } => access(self.eval(frame, base), index as usize),
+            Expression::Constant(constant_handle) => constant(self.module, constant_handle),
+            Expression::Splat { size, value } => Value::Composite(vec![self.eval(frame, value); size as usize]),
+            Expression::Swizzle { size, vector, pattern } => {
+                let vector = components(self.eval(frame, vector));
+                Value::Composite(pattern[..size as usize].iter().map(|x| vector[*x as usize].clone()).collect())
+            }
+            Expression::Compose { ty, ref components } => {
+                let values: Vec<Value> = components.iter().map(|x| self.eval(frame, *x)).collect();
+                match self.module.types[ty].inner {
+                    // vec3(v.xy, z) takes the components of the vectors in it
+                    TypeInner::Vector { size, .. } => {
+                        let scalars: Vec<Value> = values.into_iter().flat_map(|x| match x {
+                            Value::Composite(x) => x,
+                            x => vec![x],
+                        }).collect();
+                        match scalars.len() {
+                            1 => Value::Composite(vec![scalars[0].clone(); size as usize]),
+                            _ => Value::Composite(scalars),
+                        }
+                    }
+                    _ => Value::Composite(values),
+                }
+            }
+            Expression::FunctionArgument(index) => frame.arguments[index as usize].clone(),
+            Expression::GlobalVariable(global) => Value::Pointer(Root::Global(global.index()), Vec::new()),
+            Expression::LocalVariable(local) => Value::Pointer(Root::Local(local.index()), Vec::new()),
+            Expression::Load { pointer } => match self.eval(frame, pointer) {
+                Value::Pointer(root, path) => self.place(frame, root, &path).clone(),
+                pointer => panic!("load from {:?}", pointer),
+            },
+            Expression::Unary { op, expr } => map(self.eval(frame, expr), &|x| match (op, x) {
+                (UnaryOperator::Negate, Value::F32(x)) => Value::F32(-x),
+                (UnaryOperator::Negate, Value::I32(x)) => Value::I32(x.wrapping_neg()),
+                (UnaryOperator::Not, Value::Bool(x)) => Value::Bool(!x),
+                (UnaryOperator::Not, Value::U32(x)) => Value::U32(!x),
+                (UnaryOperator::Not, Value::I32(x)) => Value::I32(!x),
+                (op, x) => panic!("unsupported {:?} {:?}", op, x),
+            }),
+            Expression::Binary { op, left, right } => {
+                let left = self.eval(frame, left);
+                let right = self.eval(frame, right);
+                binary(op, left, right)
+            }
+            Expression::Select { condition, accept, reject } => {
+                let condition = self.eval(frame, condition);
+                let accept = self.eval(frame, accept);
+                let reject = self.eval(frame, reject);
+                match condition {
+                    Value::Bool(true) => accept,
+                    Value::Bool(false) => reject,
+                    condition => zip3(condition, accept, reject, &|c, a, b| if c == Value::Bool(true) { a } else { b }),
+                }
+            }
+            Expression::Relational { fun, argument } => {
+                let argument = components(self.eval(frame, argument));
+                match fun {
+                    RelationalFunction::All => Value::Bool(argument.iter().all(|x| *x == Value::Bool(true))),
+                    RelationalFunction::Any => Value::Bool(argument.contains(&Value::Bool(true))),
+                    fun => panic!("unsupported {:?}", fun),
+                }
+            }
+            Expression::Math { fun, arg, arg1, arg2, .. } => {
+                let arg = self.eval(frame, arg);
+                let arg1 = arg1.map(|x| self.eval(frame, x));
+                let arg2 = arg2.map(|x| self.eval(frame, x));
+                math(fun, arg, arg1, arg2)
+            }
+            Expression::As { expr, kind, convert } => map(self.eval(frame, expr), &|x| match (convert, kind, x) {
+                (Some(_), ScalarKind::Float, x) => Value::F32(match x {
+                    Value::F32(x) => x,
+                    Value::U32(x) => x as f32,
+                    Value::I32(x) => x as f32,
+                    x => panic!("{:?} to f32", x),
+                }),
+                (Some(_), ScalarKind::Uint, x) => Value::U32(match x {
+                    Value::F32(x) => x as u32,
+                    Value::U32(x) => x,
+                    Value::I32(x) => x as u32,
+                    x => panic!("{:?} to u32", x),
+                }),
+                (Some(_), ScalarKind::Sint, x) => Value::I32(match x {
+                    Value::F32(x) => x as i32,
+                    Value::U32(x) => x as i32,
+                    Value::I32(x) => x,
+                    x => panic!("{:?} to i32", x),
+                }),
+                // a bitcast
+                (None, kind, x) => {
+                    let bits = match x {
+                        Value::F32(x) => x.to_bits(),
+                        Value::U32(x) => x,
+                        Value::I32(x) => x as u32,
+                        x => panic!("bitcast of {:?}", x),
+                    };
+                    match kind {
+                        ScalarKind::Float => Value::F32(f32::from_bits(bits)),
+                        ScalarKind::Uint => Value::U32(bits),
+                        ScalarKind::Sint => Value::I32(bits as i32),
+                        ScalarKind::Bool => panic!("bitcast to bool"),
+                    }
+                }
+                (convert, kind, x) => panic!("unsupported cast of {:?} to {:?} {:?}", x, kind, convert),
+            }),
+            Expression::CallResult(_) => panic!("call result read before the call"),
+            ref expression => panic!("unsupported expression {:?}", expression),
+        }
+    }
+}
+
+fn constant(module: &Module, handle: Handle<naga::Constant>) -> Value {
+    match module.constants[handle].inner {
+        ConstantInner::Scalar { value, .. } => match value {
+            ScalarValue::Float(x) => Value::F32(x as f32),
+            ScalarValue::Uint(x) => Value::U32(x as u32),
+            ScalarValue::Sint(x) => Value::I32(x as i32),
+            ScalarValue::Bool(x) => Value::Bool(x),
+        },
+        ConstantInner::Composite { ref components, .. } => {
+            Value::Composite(components.iter().map(|x| constant(module, *x)).collect())
+        }
+    }
+}
+
+fn access(base: Value, index: usize) -> Value {
+    match base {
+        Value::Pointer(root, mut path) => {
+            path.push(index);
+            Value::Pointer(root, path)
+        }
+        Value::Composite(mut components) => components.swap_remove(index),
+        base => panic!("index {} into {:?}", index, base),
+    }
+}
+
+fn components(value: Value) -> Vec<Value> {
+    match value {
+        Value::Composite(components) => components,
+        value => panic!("{:?} has no components", value),
+    }
+}
+
+fn is_matrix(value: &Value) -> bool {
+    matches!(value, Value::Composite(columns) if matches!(columns.first(), Some(Value::Composite(_))))
+}
+
+fn map(x: Value, f: &dyn Fn(Value) -> Value) -> Value {
+    match x {
+        Value::Composite(x) => Value::Composite(x.into_iter().map(|x| map(x, f)).collect()),
+        x => f(x),
+    }
+}
+
+// componentwise, a scalar on either side goes with every component of the other
+fn zip(a: Value, b: Value, f: &dyn Fn(Value, Value) -> Value) -> Value {
+    match (a, b) {
+        (Value::Composite(a), Value::Composite(b)) => Value::Composite(a.into_iter().zip(b).map(|(a, b)| zip(a, b, f)).collect()),
+        (Value::Composite(a), b) => Value::Composite(a.into_iter().map(|a| zip(a, b.clone(), f)).collect()),
+        (a, Value::Composite(b)) => Value::Composite(b.into_iter().map(|b| zip(a.clone(), b, f)).collect()),
+        (a, b) => f(a, b),
+    }
+}
+
+fn zip3(a: Value, b: Value, c: Value, f: &dyn Fn(Value, Value, Value) -> Value) -> Value {
+    match (a, b, c) {
+        (Value::Composite(a), b, c) => {
+            let at = |x: &Value, i: usize| match x {
+                Value::Composite(x) => x[i].clone(),
+                x => x.clone(),
+            };
+            Value::Composite(a.into_iter().enumerate().map(|(i, a)| zip3(a, at(&b, i), at(&c, i), f)).collect())
+        }
+        (a, Value::Composite(b), c) => zip3(Value::Composite(vec![a; b.len()]), Value::Composite(b), c, f),
+        (a, b, Value::Composite(c)) => zip3(Value::Composite(vec![a; c.len()]), b, Value::Composite(c), f),
+        (a, b, c) => f(a, b, c),
+    }
+}
+
+fn float(x: &Value) -> f32 {
+    match x {
+        Value::F32(x) => *x,
+        x => panic!("expected a f32, got {:?}", x),
+    }
+}
+
+fn dot(a: Value, b: Value) -> Value {
+    let products = components(zip(a, b, &|a, b| binary(BinaryOperator::Multiply, a, b)));
+    products.into_iter().reduce(|a, b| binary(BinaryOperator::Add, a, b)).unwrap()
+}
+
+fn binary(op: BinaryOperator, left: Value, right: Value) -> Value {
+    use BinaryOperator::*;
+
+    if op == Multiply && (is_matrix(&left) || is_matrix(&right)) {
+        return match (is_matrix(&left), is_matrix(&right)) {
+            // a row vector, one dot product per column
+            (false, true) => Value::Composite(components(right).into_iter().map(|column| dot(left.clone(), column)).collect()),
+            (true, false) => {
+                let columns = components(left).into_iter().zip(components(right)).map(|(column, x)| binary(Multiply, column, x));
+                columns.reduce(|a, b| binary(Add, a, b)).unwrap()
+            }
+            _ => panic!("unsupported matrix product"),
+        };
+    }
+
+    zip(left, right, &|left, right| match (left, right) {
+        (Value::F32(a), Value::F32(b)) => match op {
+            Add => Value::F32(a + b),
+            Subtract => Value::F32(a - b),
+            Multiply => Value::F32(a * b),
+            Divide => Value::F32(a / b),
+            Modulo => Value::F32(a % b),
+            Equal => Value::Bool(a == b),
+            NotEqual => Value::Bool(a != b),
+            Less => Value::Bool(a < b),
+            LessEqual => Value::Bool(a <= b),
+            Greater => Value::Bool(a > b),
+            GreaterEqual => Value::Bool(a >= b),
+            op => panic!("unsupported f32 {:?}", op),
+        },
+        (Value::U32(a), Value::U32(b)) => match op {
+            Add => Value::U32(a.wrapping_add(b)),
+            Subtract => Value::U32(a.wrapping_sub(b)),
+            Multiply => Value::U32(a.wrapping_mul(b)),
+            Divide => Value::U32(a / b),
+            Modulo => Value::U32(a % b),
+            Equal => Value::Bool(a == b),
+            NotEqual => Value::Bool(a != b),
+            Less => Value::Bool(a < b),
+            LessEqual => Value::Bool(a <= b),
+            Greater => Value::Bool(a > b),
+            GreaterEqual => Value::Bool(a >= b),
+            And => Value::U32(a & b),
+            ExclusiveOr => Value::U32(a ^ b),
+            InclusiveOr => Value::U32(a | b),
+            ShiftLeft => Value::U32(a.wrapping_shl(b)),
+            ShiftRight => Value::U32(a.wrapping_shr(b)),
+            op => panic!("unsupported u32 {:?}", op),
+        },
+        (Value::I32(a), Value::I32(b)) => match op {
+            Add => Value::I32(a.wrapping_add(b)),
+            Subtract => Value::I32(a.wrapping_sub(b)),
+            Multiply => Value::I32(a.wrapping_mul(b)),
+            Divide => Value::I32(a / b),
+            Modulo => Value::I32(a % b),
+            Equal => Value::Bool(a == b),
+            NotEqual => Value::Bool(a != b),
+            Less => Value::Bool(a < b),
+            LessEqual => Value::Bool(a <= b),
+            Greater => Value::Bool(a > b),
+            GreaterEqual => Value::Bool(a >= b),
+            op => panic!("unsupported i32 {:?}", op),
+        },
+        (Value::Bool(a), Value::Bool(b)) => match op {
+            Equal => Value::Bool(a == b),
+            NotEqual => Value::Bool(a != b),
+            LogicalAnd | And => Value::Bool(a && b),
+            LogicalOr | InclusiveOr => Value::Bool(a || b),
+            op => panic!("unsupported bool {:?}", op),
+        },
+        (a, b) => panic!("{:?} {:?} {:?}", a, op, b),
+    })
+}
+
+fn math(fun: MathFunction, arg: Value, arg1: Option<Value>, arg2: Option<Value>) -> Value {
+    let unary = |f: fn(f32) -> f32| map(arg.clone(), &move |x| Value::F32(f(float(&x))));
+    let binary = |f: fn(f32, f32) -> f32| zip(arg.clone(), arg1.clone().unwrap(), &move |a, b| Value::F32(f(float(&a), float(&b))));
+
+    match fun {
+        MathFunction::Abs => unary(f32::abs),
+        MathFunction::Floor => unary(f32::floor),
+        MathFunction::Ceil => unary(f32::ceil),
+        MathFunction::Fract => unary(|x| x - x.floor()),
+        MathFunction::Sqrt => unary(f32::sqrt),
+        MathFunction::Sin => unary(f32::sin),
+        MathFunction::Cos => unary(f32::cos),
+        MathFunction::Exp => unary(f32::exp),
+        MathFunction::Min => binary(f32::min),
+        MathFunction::Max => binary(f32::max),
+        MathFunction::Pow => binary(f32::powf),
+        // edge, x
+        MathFunction::Step => binary(|edge, x| if x < edge { 0.0 } else { 1.0 }),
+        MathFunction::Clamp => zip3(arg, arg1.unwrap(), arg2.unwrap(), &|x, low, high| Value::F32(float(&x).max(float(&low)).min(float(&high)))),
+        MathFunction::Mix => zip3(arg, arg1.unwrap(), arg2.unwrap(), &|a, b, t| Value::F32(float(&a) * (1.0 - float(&t)) + float(&b) * float(&t))),
+        MathFunction::Dot => dot(arg, arg1.unwrap()),
+        MathFunction::Length => Value::F32(float(&dot(arg.clone(), arg)).sqrt()),
+        MathFunction::Distance => {
+            let difference = self::binary(BinaryOperator::Subtract, arg, arg1.unwrap());
+            Value::F32(float(&dot(difference.clone(), difference)).sqrt())
+        }
+        fun => panic!("unsupported {:?}", fun),
+    }
+}
+
+// a value of type ty out of a buffer, starting at offset bytes
+fn decode(module: &Module, ty: Handle<naga::Type>, words: &[u32], offset: u32) -> Value {
+    let word = |offset: u32| words[offset as usize / 4];
+    let scalar = |kind: ScalarKind, offset: u32| match kind {
+        ScalarKind::Float => Value::F32(f32::from_bits(word(offset))),
+        ScalarKind::Uint => Value::U32(word(offset)),
+        ScalarKind::Sint => Value::I32(word(offset) as i32),
+        ScalarKind::Bool => Value::Bool(word(offset) != 0),
+    };
+
+    match module.types[ty].inner {
+        TypeInner::Scalar { kind, .. } => scalar(kind, offset),
+        TypeInner::Vector { size, kind, .. } => Value::Composite((0..size as u32).map(|i| scalar(kind, offset + 4 * i)).collect()),
+        TypeInner::Array { base, size, stride } => {
+            let len = match size {
+                ArraySize::Constant(len) => match constant(module, len) {
+                    Value::U32(len) => len,
+                    Value::I32(len) => len as u32,
+                    len => panic!("array of {:?}", len),
+                },
+                // the rest of the buffer
+                ArraySize::Dynamic => (words.len() as u32 * 4 - offset) / stride,
+            };
+            Value::Composite((0..len).map(|i| decode(module, base, words, offset + i * stride)).collect())
+        }
+        TypeInner::Struct { ref members, .. } => {
+            Value::Composite(members.iter().map(|member| decode(module, member.ty, words, offset + member.offset)).collect())
+        }
+        ref inner => panic!("unsupported buffer type {:?}", inner),
+    }
+}
+
+fn scalar_bits(value: &Value) -> u32 {
+    match *value {
+        Value::F32(x) => x.to_bits(),
+        Value::U32(x) => x,
+        Value::I32(x) => x as u32,
+        Value::Bool(x) => x as u32,
+        ref value => panic!("{:?} in a scalar", value),
+    }
+}
+
+fn encode(module: &Module, ty: Handle<naga::Type>, value: &Value, words: &mut [u32], offset: u32) {
+    match (&module.types[ty].inner, value) {
+        (&TypeInner::Scalar { .. }, value) => words[offset as usize / 4] = scalar_bits(value),
+        (&TypeInner::Vector { .. }, Value::Composite(components)) => {
+            for (i, x) in components.iter().enumerate() {
+                words[offset as usize / 4 + i] = scalar_bits(x);
+            }
+        }
+        (&TypeInner::Array { base, stride, .. }, Value::Composite(components)) => {
+            for (i, x) in components.iter().enumerate() {
+                encode(module, base, x, words, offset + i as u32 * stride);
+            }
+        }
+        (TypeInner::Struct { members, .. }, Value::Composite(components)) => {
+            for (member, x) in members.iter().zip(components) {
+                encode(module, member.ty, x, words, offset + member.offset);
+            }
+        }
+        (inner, value) => panic!("{:?} in a {:?}", value, inner),
+    }
+}
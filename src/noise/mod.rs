@@ -1,15 +1,291 @@
-use bevy::{prelude::*, render::renderer::{RenderDevice, RenderQueue}};
+use bevy::{prelude::*, ecs::system::Resource, render::renderer::{RenderDevice, RenderQueue}};
 
-use self::opensimplex::*;
+use crate::world::chunk::{Chunk, PendingDensity};
 
+use self::{graph::DensityGraph, opensimplex::*, simplex::{Fractal, Simplex}};
+
+pub mod graph;
 pub mod opensimplex;
+pub mod simplex;
+
+#[cfg(test)]
+mod interpreter;
+#[cfg(test)]
+mod tests;
+
+// the fractal noise of DensityNode::Terrain and the seed of every noise node. octave after octave the
+// frequency grows by lacunarity and the amplitude shrinks by gain, the preset decides how the
+// octaves are shaped. changing it regenerates every loaded chunk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TerrainParams {
+    pub freq: f32,
+    pub amp: f32,
+    pub lacunarity: f32,
+    pub gain: f32,
+    pub octaves: u32,
+    // the same seed gives the same density on every machine, the gpu hashes it with integer math only
+    pub seed: u64,
+    pub preset: TerrainPreset,
+    // carved out of whatever the DensityGraph gives, None leaves the terrain solid
+    pub caves: Option<Caves>,
+}
+
+impl Default for TerrainParams {
+    fn default() -> Self {
+        Self {
+            freq: 0.003,
+            amp: 140.0,
+            lacunarity: 2.0,
+            gain: 0.5,
+            octaves: 10,
+            seed: 0,
+            preset: TerrainPreset::Fbm,
+            caves: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TerrainPreset {
+    // rolling hills
+    Fbm,
+    // mountain ridges. offset moves the crests, higher gives broader ridges, 1 is a good start
+    Ridged { offset: f32 },
+    // fbm sampled at a position pushed around by noise of its own, for eroded, folded shapes
+    Warped { freq: f32, strength: f32 },
+    // round hills with sharp creases between them
+    Billow,
+    // fbm cut into flat plateaus height apart
+    Terraced { height: f32 },
+}
+
+impl TerrainPreset {
+    // the preset id and its parameters as noise.wgsl's preset_terrain reads them
+    fn to_uniform(self) -> [u32; 3] {
+        let (id, a, b) = match self {
+            Self::Fbm => (0, 0.0, 0.0),
+            Self::Ridged { offset } => (1, offset, 0.0),
+            Self::Warped { freq, strength } => (2, freq, strength),
+            Self::Billow => (3, 0.0, 0.0),
+            Self::Terraced { height } => (4, height, 0.0),
+        };
+        [id, f32::to_bits(a), f32::to_bits(b)]
+    }
+}
+
+// worm tunnels where two noise fields are both close to zero and cheese caves where a third is
+// high, each within a band of heights. top is above bottom, both in world units
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Caves {
+    pub tunnel_freq: f32,
+    // how close to zero the two fields have to be, wider tunnels as it grows
+    pub tunnel_radius: f32,
+    pub tunnel_top: f32,
+    pub tunnel_bottom: f32,
+    pub cheese_freq: f32,
+    // the noise value above which it is hollow, fewer and smaller caves as it nears 1
+    pub cheese_threshold: f32,
+    pub cheese_top: f32,
+    pub cheese_bottom: f32,
+    // the distance over which the caves fade out past the ends of their bands
+    pub fade: f32,
+}
+
+impl Default for Caves {
+    fn default() -> Self {
+        Self {
+            tunnel_freq: 0.01,
+            tunnel_radius: 0.08,
+            tunnel_top: -10.0,
+            tunnel_bottom: -200.0,
+            cheese_freq: 0.006,
+            cheese_threshold: 0.45,
+            cheese_top: -60.0,
+            cheese_bottom: -400.0,
+            fade: 20.0,
+        }
+    }
+}
 
-pub struct NoisePlugin;
+impl Caves {
+    // scales the cave noise up to about the slope of the terrain
+    const SCALE: f32 = 8.0;
+    // how far below 0 the cave noise still carves, the shell around every cave
+    const SHELL: f32 = 0.25;
+
+    fn to_uniform(self) -> [u32; 9] {
+        [
+            self.tunnel_freq,
+            self.tunnel_radius,
+            self.tunnel_top,
+            self.tunnel_bottom,
+            self.cheese_freq,
+            self.cheese_threshold,
+            self.cheese_top,
+            self.cheese_bottom,
+            self.fade,
+        ].map(f32::to_bits)
+    }
+
+    fn band(&self, y: f32, top: f32, bottom: f32) -> f32 {
+        ((top - y) / self.fade).clamp(0.0, 1.0) * ((y - bottom) / self.fade).clamp(0.0, 1.0)
+    }
+
+    // caves of noise.wgsl. the density is only capped inside a cave and its shell, and the cap
+    // grows without bound towards the edge of the shell so the terrain around it keeps its slope
+    pub fn carve(&self, pos: Vec3, noise: &Simplex, density: f32) -> f32 {
+        let mix = |v: f32, band: f32| -1.0 + (v + 1.0) * band;
+
+        let q = pos * self.tunnel_freq;
+        let worm = Vec2::new(noise.simplex3d(q), noise.simplex3d(q + Vec3::new(31.4, 17.1, 5.9)));
+        let tunnel = 1.0 - worm.length() / self.tunnel_radius;
+        let tunnel = mix(tunnel, self.band(pos.y, self.tunnel_top, self.tunnel_bottom));
+
+        let n = noise.simplex3d(pos * self.cheese_freq + Vec3::new(-13.7, 7.3, 23.1));
+        let cheese = (n - self.cheese_threshold) / (1.0 - self.cheese_threshold);
+        let cheese = mix(cheese, self.band(pos.y, self.cheese_top, self.cheese_bottom));
+
+        let cave = tunnel.max(cheese);
+        if cave <= -Self::SHELL {
+            return density;
+        }
+        density.min(-cave * Self::SCALE / (1.0 + cave / Self::SHELL))
+    }
+}
+
+impl TerrainParams {
+    // the Params struct of noise.wgsl, a multiple of 16 bytes as it is
+    pub fn to_uniform(self) -> [u32; 20] {
+        let [preset, preset_a, preset_b] = self.preset.to_uniform();
+        let [tunnel_freq, tunnel_radius, tunnel_top, tunnel_bottom, cheese_freq, cheese_threshold, cheese_top, cheese_bottom, fade] =
+            self.caves.unwrap_or_default().to_uniform();
+        [
+            self.freq.to_bits(),
+            self.amp.to_bits(),
+            self.lacunarity.to_bits(),
+            self.gain.to_bits(),
+            self.octaves,
+            self.seed as u32,
+            (self.seed >> 32) as u32,
+            preset,
+            preset_a,
+            preset_b,
+            self.caves.is_some() as u32,
+            tunnel_freq,
+            tunnel_radius,
+            tunnel_top,
+            tunnel_bottom,
+            cheese_freq,
+            cheese_threshold,
+            cheese_top,
+            cheese_bottom,
+            fade,
+        ]
+    }
+
+    // the simplex3d of noise.wgsl, for DensityGraph::eval
+    pub fn simplex(&self) -> Simplex {
+        Simplex::new(self.seed)
+    }
+
+    pub fn fractal(&self) -> Fractal {
+        Fractal {
+            freq: self.freq,
+            amp: self.amp,
+            lacunarity: self.lacunarity,
+            gain: self.gain,
+            octaves: self.octaves,
+        }
+    }
+
+    // preset_terrain of noise.wgsl
+    pub fn terrain(&self, pos: Vec3, noise: &Simplex) -> f32 {
+        let fractal = self.fractal();
+
+        match self.preset {
+            TerrainPreset::Fbm => noise.fbm(pos, &fractal),
+            TerrainPreset::Ridged { offset } => noise.ridged(pos, &fractal, offset),
+            TerrainPreset::Warped { freq, strength } => noise.fbm(noise.warp(pos, freq, strength), &fractal),
+            TerrainPreset::Billow => noise.billow(pos, &fractal),
+            TerrainPreset::Terraced { height } => simplex::terrace(noise.fbm(pos, &fractal), height),
+        }
+    }
+}
+
+// where the density of new chunks gets computed. both evaluate the same DensityGraph
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DensityBackend {
+    // the graph compiled into noise.wgsl, in batches
+    Gpu,
+    // DensityGraph::eval on the AsyncComputeTaskPool, one task per chunk
+    Cpu,
+}
+
+pub struct NoisePlugin {
+    // falls back to Cpu without a RenderDevice
+    pub backend: DensityBackend,
+}
+
+impl Default for NoisePlugin {
+    fn default() -> Self {
+        Self {
+            backend: DensityBackend::Gpu,
+        }
+    }
+}
 
 impl Plugin for NoisePlugin {
     fn build(&self, app: &mut App) {
         app
-            .init_resource::<OpenSimplex>()
+            .init_resource::<TerrainParams>()
+            .init_resource::<DensityGraph>();
+
+        let has_gpu = app.world.get_resource::<RenderDevice>().is_some();
+        let backend = if self.backend == DensityBackend::Gpu && has_gpu {
+            app
+                .init_resource::<OpenSimplex>()
+                .add_system_to_stage(CoreStage::PreUpdate, upload_terrain_system);
+            DensityBackend::Gpu
+        } else {
+            DensityBackend::Cpu
+        };
+
+        app
+            .insert_resource(backend)
+            .add_system_to_stage(CoreStage::PreUpdate, regenerate_terrain_system)
         ;
     }
-}
\ No newline at end of file
+}
+
+// the buffer and the pipeline start out with what the resources were added with
+fn terrain_changed<T: Resource>(resource: &Res<T>) -> bool {
+    resource.is_changed() && !resource.is_added()
+}
+
+fn upload_terrain_system(
+    params: Res<TerrainParams>,
+    graph: Res<DensityGraph>,
+    mut simplex: ResMut<OpenSimplex>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    if terrain_changed(&params) {
+        simplex.set_params(&params, &render_queue);
+    }
+    if terrain_changed(&graph) {
+        simplex.set_graph(&graph, &render_device);
+    }
+}
+
+fn regenerate_terrain_system(
+    mut commands: Commands,
+    params: Res<TerrainParams>,
+    graph: Res<DensityGraph>,
+    chunks: Query<Entity, With<Chunk>>,
+) {
+    if !terrain_changed(&params) && !terrain_changed(&graph) {return}
+
+    for entity in chunks.iter() {
+        commands.entity(entity).insert(PendingDensity);
+    }
+}
@@ -6,34 +6,59 @@ use bevy::{
         render_resource::*, 
         renderer::{RenderDevice, RenderQueue}
     },
-    core::{cast_slice, Pod}, utils::Instant,
+    core::{cast_slice, Pod}, tasks::AsyncComputeTaskPool,
 };
 
-use crate::world::chunk::BUFFER_SIZE;
+use crate::world::{
+    chunk::{ChunkDims, ChunkSettings, BATCH_SIZE},
+    readback::ReadbackRing,
+};
+
+use super::{graph::DensityGraph, TerrainParams};
 
 
+// buffers for BATCH_SIZE chunks
 struct SimplexCumputeBuffers {
     pos_buffer: Buffer,
     values_buffer: Buffer,
+    dims_buffer: Buffer,
+    params_buffer: Buffer,
+    staging: ReadbackRing<Vec<(Entity, u32)>>,
 }
 
 impl SimplexCumputeBuffers {
-    fn new_empty(render_device: &RenderDevice, buffer_size: u64) -> Self{
+    fn new_empty(render_device: &RenderDevice, dims: ChunkDims, params: &TerrainParams) -> Self{
+        let buffer_size = (dims.buffer_size() * BATCH_SIZE) as u64;
+
         let pos_buffer = render_device.create_buffer(&BufferDescriptor {
             label: Some("simplex pos buffer"),
-            size: std::mem::size_of::<Vec4>() as u64,
-            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            size: (std::mem::size_of::<Vec4>() * BATCH_SIZE) as u64,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
 
         let values_buffer = render_device.create_buffer(&BufferDescriptor {
             label: Some("simplex values buffer"),
             size: std::mem::size_of::<f32>() as u64 * buffer_size,
-            usage: BufferUsages::STORAGE | BufferUsages::MAP_READ,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
             mapped_at_creation: false,
         });
+
+        let dims_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("simplex dims buffer"),
+            contents: cast_slice(&[dims.axis_size as u32, 0, 0, 0]),
+            usage: BufferUsages::UNIFORM,
+        });
+
+        let params_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("simplex params buffer"),
+            contents: cast_slice(&params.to_uniform()),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
         
-        Self {pos_buffer, values_buffer}
+        let staging = ReadbackRing::new(render_device, "simplex staging buffer", std::mem::size_of::<f32>() as u64 * buffer_size);
+
+        Self {pos_buffer, values_buffer, dims_buffer, params_buffer, staging}
     }
 }
 
@@ -44,14 +69,33 @@ pub struct  OpenSimplex {
 }
 
 impl OpenSimplex {
-    pub fn compute_chunk(
-        &self,
-        pos: Vec3,
+    // chunks dispatched from now on use these, the ones already in flight keep the old ones
+    pub fn set_params(&self, params: &TerrainParams, render_queue: &RenderQueue) {
+        render_queue.write_buffer(&self.compute_buffers.params_buffer, 0, cast_slice(&params.to_uniform()));
+    }
+
+    // recompiles the shader, same as set_params for the chunks in flight
+    pub fn set_graph(&mut self, graph: &DensityGraph, render_device: &RenderDevice) {
+        self.simplex_pipeline = simplex_pipeline(render_device, &self.buffer_bind_group_layout, graph);
+    }
+
+    // queues the density of up to BATCH_SIZE chunks as (entity, generation, origin, sample distance)
+    // in one dispatch, false if all staging buffers are in use.
+    // the values come back through collect_chunks a few frames later, not necessarily in order
+    pub fn dispatch_chunks(
+        &mut self,
+        chunks: &[(Entity, u32, Vec3, f32)],
+        dims: ChunkDims,
         render_device: &RenderDevice,
         render_queue: &RenderQueue,
-    ) -> [f32; BUFFER_SIZE]{
+        task_pool: &AsyncComputeTaskPool,
+    ) -> bool {
+        assert!(chunks.len() <= BATCH_SIZE);
 
-        let start = Instant::now();
+        let slot = match self.compute_buffers.staging.free_slot() {
+            Some(slot) => slot,
+            None => return false,
+        };
 
         let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
             label: None,
@@ -65,11 +109,19 @@ impl OpenSimplex {
                     binding: 1,
                     resource: self.compute_buffers.values_buffer.as_entire_binding()
                 },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: self.compute_buffers.dims_buffer.as_entire_binding()
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: self.compute_buffers.params_buffer.as_entire_binding()
+                },
             ],
         });
 
-        let pos = &[Vec4::new(pos.x, pos.y, pos.z, 0.0)];
-        let bytes: &[u8] = cast_slice(pos);
+        let positions: Vec<Vec4> = chunks.iter().map(|(_, _, pos, scale)| pos.extend(*scale)).collect();
+        let bytes: &[u8] = cast_slice(&positions);
         render_queue.write_buffer(&self.compute_buffers.pos_buffer, 0, &bytes[..]);
 
         let mut command_encoder = render_device.create_command_encoder(&CommandEncoderDescriptor { label: Some("simplex command encoder") });
@@ -77,41 +129,43 @@ impl OpenSimplex {
             let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor::default());
             pass.set_pipeline(&self.simplex_pipeline);
             pass.set_bind_group(0, &bind_group, &[]);
-            pass.dispatch(4, 4, 4)
+            pass.dispatch(dims.workgroups(), dims.workgroups(), dims.workgroups() * chunks.len() as u32)
         }
+        let size = (std::mem::size_of::<f32>() * dims.buffer_size() * chunks.len()) as u64;
+        command_encoder.copy_buffer_to_buffer(&self.compute_buffers.values_buffer, 0, self.compute_buffers.staging.buffer(slot), 0, size);
         render_queue.submit(once(command_encoder.finish()));
-        
-        let mut values: [f32; BUFFER_SIZE] = [0.0; BUFFER_SIZE];
-    
-        {
-            let slice = &self.compute_buffers.values_buffer.slice(..);
-            render_device.map_buffer(slice, MapMode::Read);
-            let buff_out = &slice.get_mapped_range()[..];
-            let buff_out: &[f32] = cast_slice(buff_out);
-            
-            for i in 0..BUFFER_SIZE {
-                values[i] = buff_out[i];
-            }
-            let elapsed = start.elapsed();
-        }
-        self.compute_buffers.values_buffer.unmap();
-        
 
-        values
+        let entities = chunks.iter().map(|(entity, generation, ..)| (*entity, *generation)).collect();
+        self.compute_buffers.staging.map(slot, entities, task_pool);
+        true
+    }
+
+    // hands the values of every finished chunk to f, with the generation they were queued with
+    pub fn collect_chunks(&mut self, dims: ChunkDims, render_device: &RenderDevice, mut f: impl FnMut(Entity, u32, Vec<f32>)) {
+        self.compute_buffers.staging.collect(render_device, |batch, bytes| {
+            let values: &[f32] = cast_slice(bytes);
+            for ((entity, generation), values) in batch.into_iter().zip(values.chunks_exact(dims.buffer_size())) {
+                f(entity, generation, values.to_vec());
+            }
+        });
     }
 }
 
 impl FromWorld for OpenSimplex {
     fn from_world(world: &mut World) -> Self {
         let render_device = world.get_resource::<RenderDevice>().unwrap();
-        
-       
-        let shader_source = include_str!("../../assets/shaders/noise.wgsl");
-        let shader = render_device.create_shader_module(&ShaderModuleDescriptor {
-            label: None,
-            source: ShaderSource::Wgsl(shader_source.into()),
-        });
 
+        // ChunkPlugin has to be added first so we know how big the chunks are
+        let dims = world.get_resource::<ChunkSettings>().unwrap().dims;
+        let params = world.get_resource::<TerrainParams>().unwrap();
+        let graph = world.get_resource::<DensityGraph>().unwrap();
+
+        Self::new(render_device, dims, params, graph)
+    }
+}
+
+impl OpenSimplex {
+    pub fn new(render_device: &RenderDevice, dims: ChunkDims, params: &TerrainParams, graph: &DensityGraph) -> Self {
         let buffer_bind_group_layout =
             render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
                 label: None,
@@ -120,7 +174,7 @@ impl FromWorld for OpenSimplex {
                         binding: 0,
                         visibility: ShaderStages::COMPUTE,
                         ty: BindingType::Buffer { 
-                            ty: BufferBindingType::Uniform, 
+                            ty: BufferBindingType::Storage { read_only: true }, 
                             has_dynamic_offset: false, 
                             min_binding_size: None
                         },
@@ -135,24 +189,32 @@ impl FromWorld for OpenSimplex {
                             min_binding_size: None
                         },
                         count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer { 
+                            ty: BufferBindingType::Uniform, 
+                            has_dynamic_offset: false, 
+                            min_binding_size: None
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer { 
+                            ty: BufferBindingType::Uniform, 
+                            has_dynamic_offset: false, 
+                            min_binding_size: None
+                        },
+                        count: None,
                     }
                 ]
             });
 
-        let compute_buffers = SimplexCumputeBuffers::new_empty(render_device, BUFFER_SIZE as u64);
-
-        let pipeline_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
-            label: None,
-            bind_group_layouts: &[&buffer_bind_group_layout],
-            push_constant_ranges: &[],
-        });
-        
-        let simplex_pipeline = render_device.create_compute_pipeline(&RawComputePipelineDescriptor {
-            label: None,
-            layout: Some(&pipeline_layout),
-            module: &shader,
-            entry_point: "main",
-        });
+        let compute_buffers = SimplexCumputeBuffers::new_empty(render_device, dims, params);
+        let simplex_pipeline = simplex_pipeline(render_device, &buffer_bind_group_layout, graph);
 
         Self {
             buffer_bind_group_layout,
@@ -160,4 +222,30 @@ impl FromWorld for OpenSimplex {
             compute_buffers,
         }
     }
+}
+
+// noise.wgsl with the graph compiled in as its terrain function
+pub fn shader_source(graph: &DensityGraph) -> String {
+    include_str!("../../assets/shaders/noise.wgsl").replace("// #terrain", &graph.to_wgsl())
+}
+
+fn simplex_pipeline(render_device: &RenderDevice, layout: &BindGroupLayout, graph: &DensityGraph) -> ComputePipeline {
+    let shader_source = shader_source(graph);
+    let shader = render_device.create_shader_module(&ShaderModuleDescriptor {
+        label: None,
+        source: ShaderSource::Wgsl(shader_source.into()),
+    });
+
+    let pipeline_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
+        label: None,
+        bind_group_layouts: &[layout],
+        push_constant_ranges: &[],
+    });
+
+    render_device.create_compute_pipeline(&RawComputePipelineDescriptor {
+        label: None,
+        layout: Some(&pipeline_layout),
+        module: &shader,
+        entry_point: "main",
+    })
 }
\ No newline at end of file
@@ -0,0 +1,133 @@
+use bevy::{prelude::*, math::Vec3Swizzles};
+
+// the noise functions of noise.wgsl on the cpu, written out operation for operation so both
+// give the same terrain. keep the two in sync
+
+const F3: f32 = 0.3333333;
+const G3: f32 = 0.1666667;
+
+// octave after octave the frequency grows by lacunarity and the amplitude shrinks by gain
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fractal {
+    pub freq: f32,
+    pub amp: f32,
+    pub lacunarity: f32,
+    pub gain: f32,
+    pub octaves: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Simplex {
+    // pcg3d of the seed, random3 mixes it into every lattice point
+    seed: [u32; 3],
+}
+
+impl Simplex {
+    pub fn new(seed: u64) -> Self {
+        let (lo, hi) = (seed as u32, (seed >> 32) as u32);
+        Self { seed: pcg3d([lo, hi, lo ^ hi]) }
+    }
+
+    // random vector in [-0.5, 0.5) for a lattice point c, which holds whole numbers
+    fn random3(&self, c: Vec3) -> Vec3 {
+        let c = [c.x as i32 as u32, c.y as i32 as u32, c.z as i32 as u32];
+        let h = pcg3d([c[0] ^ self.seed[0], c[1] ^ self.seed[1], c[2] ^ self.seed[2]]);
+        Vec3::new((h[0] >> 8) as f32, (h[1] >> 8) as f32, (h[2] >> 8) as f32) / 16777216.0 - 0.5
+    }
+
+    pub fn simplex3d(&self, p: Vec3) -> f32 {
+        let s = (p + p.dot(Vec3::splat(F3))).floor();
+        let x = p - s + s.dot(Vec3::splat(G3));
+
+        let e = step(Vec3::ZERO, x - x.yzx());
+        let i1 = e * (1.0 - e.zxy());
+        let i2 = 1.0 - e.zxy() * (1.0 - e);
+
+        let x1 = x - i1 + G3;
+        let x2 = x - i2 + 2.0 * G3;
+        let x3 = x - 1.0 + 3.0 * G3;
+
+        let w = (0.6 - Vec4::new(x.dot(x), x1.dot(x1), x2.dot(x2), x3.dot(x3))).max(Vec4::ZERO);
+        let d = Vec4::new(
+            self.random3(s).dot(x),
+            self.random3(s + i1).dot(x1),
+            self.random3(s + i2).dot(x2),
+            self.random3(s + 1.0).dot(x3),
+        );
+
+        let w = w * w;
+        let w = w * w;
+        (d * w).dot(Vec4::splat(52.0))
+    }
+
+    pub fn fbm(&self, p: Vec3, fractal: &Fractal) -> f32 {
+        let (mut freq, mut amp) = (fractal.freq, fractal.amp);
+        let mut sum = 0.0;
+        for _ in 0..fractal.octaves {
+            sum += self.simplex3d(p * freq) * amp;
+            amp *= fractal.gain;
+            freq *= fractal.lacunarity;
+        }
+        sum
+    }
+
+    pub fn ridged(&self, p: Vec3, fractal: &Fractal, offset: f32) -> f32 {
+        let (mut freq, mut amp) = (fractal.freq, fractal.amp);
+        let mut weight = 1.0;
+        let mut sum = 0.0;
+        for _ in 0..fractal.octaves {
+            let n = offset - self.simplex3d(p * freq).abs();
+            let n = n * n * weight;
+            weight = (n * 2.0).clamp(0.0, 1.0);
+            sum += n * amp;
+            amp *= fractal.gain;
+            freq *= fractal.lacunarity;
+        }
+        sum
+    }
+
+    pub fn billow(&self, p: Vec3, fractal: &Fractal) -> f32 {
+        let (mut freq, mut amp) = (fractal.freq, fractal.amp);
+        let mut sum = 0.0;
+        for _ in 0..fractal.octaves {
+            sum += self.simplex3d(p * freq).abs() * amp;
+            amp *= fractal.gain;
+            freq *= fractal.lacunarity;
+        }
+        sum
+    }
+
+    pub fn warp(&self, p: Vec3, freq: f32, strength: f32) -> Vec3 {
+        let q = p * freq;
+        p + Vec3::new(
+            self.simplex3d(q),
+            self.simplex3d(q + Vec3::new(5.2, 1.3, 2.8)),
+            self.simplex3d(q + Vec3::new(1.7, 9.2, 3.4)),
+        ) * strength
+    }
+}
+
+pub fn terrace(v: f32, height: f32) -> f32 {
+    let t = v / height;
+    let f = t.floor();
+    let r = (t - f) * (t - f);
+    let r = r * r;
+    (f + r * r) * height
+}
+
+// the u32 math wraps like it does on the gpu
+fn pcg3d(v: [u32; 3]) -> [u32; 3] {
+    let mut v = v.map(|x| x.wrapping_mul(1664525).wrapping_add(1013904223));
+    v[0] = v[0].wrapping_add(v[1].wrapping_mul(v[2]));
+    v[1] = v[1].wrapping_add(v[2].wrapping_mul(v[0]));
+    v[2] = v[2].wrapping_add(v[0].wrapping_mul(v[1]));
+    v = v.map(|x| x ^ (x >> 16));
+    v[0] = v[0].wrapping_add(v[1].wrapping_mul(v[2]));
+    v[1] = v[1].wrapping_add(v[2].wrapping_mul(v[0]));
+    v[2] = v[2].wrapping_add(v[0].wrapping_mul(v[1]));
+    v
+}
+
+fn step(edge: Vec3, x: Vec3) -> Vec3 {
+    Vec3::select(x.cmpge(edge), Vec3::ONE, Vec3::ZERO)
+}
@@ -0,0 +1,257 @@
+use std::{sync::Arc, time::Duration};
+
+use bevy::{
+    prelude::*,
+    core::cast_slice,
+    render::renderer::{RenderDevice, RenderQueue},
+    tasks::{AsyncComputeTaskPool, TaskPool},
+};
+use futures_lite::future;
+
+use crate::world::chunk::ChunkDims;
+
+use super::{
+    graph::{DensityGraph, DensityNode},
+    interpreter::Interpreter,
+    opensimplex::{shader_source, OpenSimplex},
+    simplex::{Fractal, Simplex},
+    Caves, TerrainParams, TerrainPreset,
+};
+
+// the backends round differently, the gpu may fuse multiplies and adds
+const EPSILON: f32 = 1e-3;
+
+const FRACTAL: Fractal = Fractal { freq: 0.02, amp: 12.0, lacunarity: 2.0, gain: 0.5, octaves: 4 };
+
+// every node once, a node that differs between the two sides fails the parity test
+fn every_node() -> DensityGraph {
+    let ground = DensityNode::Height(-4.0) + DensityNode::Terrain + DensityNode::Ridged(FRACTAL).terrace(6.0);
+    let blob = DensityNode::Sphere { center: Vec3::new(8.0, -2.0, 12.0), radius: 10.0 };
+    let carve = DensityNode::Cuboid { center: Vec3::new(-6.0, 0.0, 4.0), half_size: Vec3::new(5.0, 3.0, 8.0) }
+        .warp(0.05, 4.0);
+    let bound = (DensityNode::Fbm(FRACTAL) + DensityNode::Noise { freq: 0.1, amp: 3.0 })
+        .scale(-1.0)
+        .clamp(-50.0, 50.0);
+    // keeps the intersection from cutting everything away
+    let bound = bound + DensityNode::Constant(40.0);
+
+    DensityGraph(
+        ground
+            .smooth_union(blob, 4.0)
+            .subtract(carve)
+            .intersect(bound)
+            .union(DensityNode::Constant(-200.0)),
+    )
+}
+
+// the default graph with every preset, then every node, then every node with caves
+fn cases() -> Vec<(DensityGraph, TerrainParams)> {
+    let params = TerrainParams { seed: 0x5eed_0000_1234, ..Default::default() };
+    let presets = [
+        TerrainPreset::Fbm,
+        TerrainPreset::Ridged { offset: 1.0 },
+        TerrainPreset::Warped { freq: 0.01, strength: 20.0 },
+        TerrainPreset::Billow,
+        TerrainPreset::Terraced { height: 16.0 },
+    ];
+
+    presets.iter()
+        .map(|preset| (DensityGraph::default(), TerrainParams { preset: *preset, ..params }))
+        .chain([
+            (every_node(), params),
+            (every_node(), TerrainParams { caves: Some(Caves { tunnel_top: 40.0, cheese_top: 20.0, ..Default::default() }), ..params }),
+        ])
+        .collect()
+}
+
+// pins the hash and the noise down to the bit, a change here changes every world
+#[test]
+fn simplex_is_reproducible() {
+    let noise = Simplex::new(42);
+    let samples = [
+        Vec3::new(0.3, 0.7, 1.1),
+        Vec3::new(-12.25, 3.5, 100.125),
+        Vec3::new(1000.0, -0.001, 7.77),
+    ];
+
+    let bits: Vec<u32> = samples.iter().map(|x| noise.simplex3d(*x).to_bits()).collect();
+    assert_eq!(bits, [0xbe4d_952c, 0x3d2f_f9e0, 0x3dab_540c]);
+}
+
+#[test]
+fn seed_changes_noise() {
+    let pos = Vec3::new(0.3, 0.7, 1.1);
+    let a = Simplex::new(1).simplex3d(pos);
+    let b = Simplex::new(2).simplex3d(pos);
+    let c = Simplex::new(1 << 32).simplex3d(pos);
+    assert_ne!(a, b);
+    assert_ne!(a, c);
+}
+
+// the density the cpu backend fills chunks with, not just the noise under it
+#[test]
+fn seed_drives_chunk_density() {
+    let dims = ChunkDims::default();
+    let origin = Vec3::new(-32.0, -16.0, 64.0);
+    let density = |seed| {
+        let params = TerrainParams { seed, ..Default::default() };
+        DensityGraph::default().eval_chunk(origin, 1.0, dims, &params)
+    };
+    let bits = |x: Vec<f32>| -> Vec<u32> { x.into_iter().map(f32::to_bits).collect() };
+
+    assert_eq!(bits(density(7)), bits(density(7)));
+    assert_ne!(bits(density(7)), bits(density(8)));
+    assert_ne!(bits(density(7)), bits(density(7 | 1 << 32)));
+}
+
+#[test]
+fn simplex_stays_in_range() {
+    let noise = Simplex::new(7);
+    for i in 0..10000 {
+        let pos = Vec3::new(i as f32 * 0.137, i as f32 * -0.071, i as f32 * 0.013);
+        let value = noise.simplex3d(pos);
+        assert!((-1.0..=1.0).contains(&value), "{} at {}", value, pos);
+    }
+}
+
+// solid ground far from the surface keeps its density, except where a cave runs through it
+#[test]
+fn caves_only_carve_near_caves() {
+    let caves = Caves::default();
+    let noise = Simplex::new(3);
+    let solid = 1000.0;
+
+    // above and below both bands
+    for y in [0.0, caves.tunnel_top + 1.0, caves.cheese_bottom - caves.fade - 1.0, -1000.0] {
+        for x in 0..100 {
+            let pos = Vec3::new(x as f32 * 7.3, y, x as f32 * -3.1);
+            assert_eq!(caves.carve(pos, &noise, solid), solid, "carved at {}", pos);
+        }
+    }
+
+    // inside the bands some of it is hollow, most of it isn't touched
+    let carved: Vec<f32> = (0..4000)
+        .map(|i| Vec3::new((i % 64) as f32 * 5.0, -100.0, (i / 64) as f32 * 5.0))
+        .map(|pos| caves.carve(pos, &noise, solid))
+        .collect();
+    assert!(carved.iter().any(|x| *x < 0.0), "no caves");
+    assert!(carved.iter().filter(|x| **x == solid).count() > carved.len() / 2, "caves everywhere");
+}
+
+#[test]
+fn compiled_graphs_validate() {
+    for graph in [DensityGraph::default(), every_node()] {
+        let source = shader_source(&graph);
+        let module = naga::front::wgsl::parse_str(&source).unwrap_or_else(|e| panic!("{:?}\n{}", e, graph.to_wgsl()));
+        naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::empty())
+            .validate(&module)
+            .unwrap_or_else(|e| panic!("{:?}\n{}", e, graph.to_wgsl()));
+    }
+}
+
+// noise.wgsl run on the cpu through naga, so the shader is checked even without a wgpu adapter.
+// an interpreted chunk takes a while, every STRIDE-th sample is enough to catch a wrong node
+#[test]
+fn shader_matches_cpu() {
+    const STRIDE: usize = 97;
+    let dims = ChunkDims::default();
+    let chunks = [(Vec3::ZERO, 1.0), (Vec3::new(-96.0, 32.0, 64.0), 4.0)];
+    let stride = dims.workgroups() * 8;
+
+    for (graph, params) in cases() {
+        let module = naga::front::wgsl::parse_str(&shader_source(&graph)).unwrap();
+        let mut shader = Interpreter::new(&module);
+
+        let positions: Vec<Vec4> = chunks.iter().map(|(origin, scale)| origin.extend(*scale)).collect();
+        let mut values = vec![0; dims.buffer_size() * chunks.len()];
+        shader.bind(0, 0, cast_slice(&positions));
+        shader.bind(0, 1, &values);
+        shader.bind(0, 2, &[dims.axis_size as u32, 0, 0, 0]);
+        shader.bind(0, 3, &params.to_uniform());
+
+        let samples = (0..dims.buffer_size()).step_by(STRIDE);
+        let ids = (0..chunks.len() as u32).flat_map(|chunk| samples.clone().map(move |i| {
+            let id = dims.from_index(i).as_uvec3();
+            [id.x, id.y, id.z + chunk * stride]
+        }));
+        shader.dispatch("main", ids);
+        shader.read(0, 1, &mut values);
+
+        for (chunk, (origin, scale)) in chunks.iter().enumerate() {
+            let cpu = graph.eval_chunk(*origin, *scale, dims, &params);
+            for i in samples.clone() {
+                let shader = f32::from_bits(values[chunk * dims.buffer_size() + i]);
+                assert!(
+                    (shader - cpu[i]).abs() <= EPSILON * cpu[i].abs().max(1.0),
+                    "sample {} of the chunk at {}: shader {} cpu {}", i, origin, shader, cpu[i],
+                );
+            }
+        }
+    }
+}
+
+// the same on a real adapter, the fallback one if there is no gpu. passes without running if
+// wgpu finds no adapter at all
+#[test]
+fn gpu_matches_cpu() {
+    let (render_device, render_queue) = match request_device() {
+        Some(device) => device,
+        None => {
+            eprintln!("no wgpu adapter, not even a fallback one, skipping gpu_matches_cpu");
+            return;
+        }
+    };
+    let task_pool = AsyncComputeTaskPool(TaskPool::new());
+    let dims = ChunkDims::default();
+
+    // a chunk at the origin and a coarser one further out, as (origin, sample distance)
+    let chunks = [(Vec3::ZERO, 1.0), (Vec3::new(-96.0, 32.0, 64.0), 4.0)];
+
+    for (graph, params) in cases() {
+        let mut simplex = OpenSimplex::new(&render_device, dims, &params, &graph);
+
+        let batch: Vec<(Entity, u32, Vec3, f32)> = chunks.iter()
+            .enumerate()
+            .map(|(i, (origin, scale))| (Entity::from_raw(i as u32), 0, *origin, *scale))
+            .collect();
+        assert!(simplex.dispatch_chunks(&batch, dims, &render_device, &render_queue, &task_pool));
+
+        let mut results = Vec::new();
+        for _ in 0..1000 {
+            simplex.collect_chunks(dims, &render_device, |entity, _, points| results.push((entity, points)));
+            if !results.is_empty() {break}
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(results.len(), chunks.len(), "the gpu never returned the density");
+
+        for (entity, gpu) in results {
+            let (origin, scale) = chunks[entity.id() as usize];
+            let cpu = graph.eval_chunk(origin, scale, dims, &params);
+
+            for (i, (gpu, cpu)) in gpu.iter().zip(cpu.iter()).enumerate() {
+                assert!(
+                    (gpu - cpu).abs() <= EPSILON * cpu.abs().max(1.0),
+                    "sample {} of the chunk at {}: gpu {} cpu {}", i, origin, gpu, cpu,
+                );
+            }
+        }
+    }
+}
+
+// the gpu if there is one, a software adapter otherwise
+fn request_device() -> Option<(RenderDevice, RenderQueue)> {
+    let instance = wgpu::Instance::new(wgpu::Backends::all());
+    let adapter = [false, true].into_iter().find_map(|force_fallback_adapter| {
+        future::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions { force_fallback_adapter, ..Default::default() }))
+    })?;
+    let (device, queue) = future::block_on(adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            label: None,
+            features: wgpu::Features::empty(),
+            limits: adapter.limits(),
+        },
+        None,
+    )).ok()?;
+
+    Some((RenderDevice::from(Arc::new(device)), Arc::new(queue)))
+}
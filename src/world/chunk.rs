@@ -1,49 +1,86 @@
 use bevy::{
     prelude::*, 
+    utils::HashMap,
     math::Vec4Swizzles, 
     render::{
         render_resource::*, 
         mesh::Indices,
         renderer::{RenderDevice, RenderQueue}
     }, 
-    core::{cast_slice, Pod}, tasks::{ComputeTaskPool, AsyncComputeTaskPool, Task}, pbr::wireframe::*,
+    core::{cast_slice, Pod}, ecs::schedule::ShouldRun, tasks::{ComputeTaskPool, AsyncComputeTaskPool, Task}, pbr::wireframe::*,
 };
 
 use bytemuck::Zeroable;
-use opensimplex_noise_rs::OpenSimplexNoise;
-use std::{iter::once, sync::Arc, ops::RangeInclusive};
+use std::{borrow::Cow, iter::once, sync::Arc};
 use std::time::Instant;
 use futures_lite::future;
 
 use crate::{
-    noise::opensimplex::OpenSimplex,
+    noise::{graph::DensityGraph, opensimplex::OpenSimplex, DensityBackend, TerrainParams},
     materials::chunk_material::*,
 };
 
+use super::{
+    gpu_mesh::{ChunkMeshData, GpuChunkMesh, GpuMeshPlugin, ReadbackMesh},
+    extractor::{MarchingCubes, SurfaceExtractor},
+    lod::{self, ChunkKey, LodSelection},
+    mesher::Field,
+    readback::ReadbackRing,
+};
 
-pub const AXIS_SIZE: usize = 32;
-pub const BUFFER_SIZE: usize = AXIS_SIZE * AXIS_SIZE * AXIS_SIZE;
 
-// big brain bit masks and shifts
-pub const Y_MASK: usize = 0b_0111_1100_0000_0000;
-pub const Z_MASK: usize = 0b_0000_0011_1110_0000;
-pub const X_MASK: usize = 0b_0000_0000_0001_1111;
+// an even number of cells, so the borders of every chunk lie on the lattice of the coarser lods
+pub const AXIS_SIZE: usize = 31;
 
-pub const Y_SHIFT: usize = 10;
-pub const Z_SHIFT: usize = 5;
-pub const X_SHIFT: usize = 0;
+// one extra sample on every side so gradients at the chunk border see the neighbour's values
+pub const PADDING: usize = 1;
 
+// chunks that share one dispatch and one readback
+pub const BATCH_SIZE: usize = 8;
 
-pub fn to_index(local: IVec3) -> usize {
-    (local.x << X_SHIFT | local.y << Y_SHIFT | local.z << Z_SHIFT) as usize
+// number of samples along one axis of a chunk, padding included
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkDims {
+    pub axis_size: usize,
 }
 
-fn from_index(index: usize) -> IVec3 {
-    IVec3::new(
-        ((index & X_MASK) >> X_SHIFT) as i32,
-        ((index & Y_MASK) >> Y_SHIFT) as i32,
-        ((index & Z_MASK) >> Z_SHIFT) as i32,
-    )
+impl ChunkDims {
+    pub fn new(axis_size: usize) -> Self {
+        assert!(axis_size > 2 * PADDING + 1, "chunks need at least one cell besides the padding");
+        Self { axis_size }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.axis_size * self.axis_size * self.axis_size
+    }
+
+    // cells that end up in the mesh along one axis, which is also the spacing between chunks
+    pub fn cells(&self) -> usize {
+        self.axis_size - 1 - 2 * PADDING
+    }
+
+    // the kernels run in 8x8x8 workgroups
+    pub fn workgroups(&self) -> u32 {
+        ((self.axis_size + 7) / 8) as u32
+    }
+
+    pub fn to_index(&self, local: IVec3) -> usize {
+        local.x as usize + local.z as usize * self.axis_size + local.y as usize * self.axis_size * self.axis_size
+    }
+
+    pub fn from_index(&self, index: usize) -> IVec3 {
+        IVec3::new(
+            (index % self.axis_size) as i32,
+            (index / (self.axis_size * self.axis_size)) as i32,
+            (index / self.axis_size % self.axis_size) as i32,
+        )
+    }
+}
+
+impl Default for ChunkDims {
+    fn default() -> Self {
+        Self::new(AXIS_SIZE)
+    }
 }
 //         e6
 //     7-------6
@@ -55,18 +92,53 @@ fn from_index(index: usize) -> IVec3 {
 //  0-------1
 //      e0
 
-#[derive(Component, Clone, Debug, Copy)]
+#[derive(Component, Clone, Debug)]
 pub struct Chunk {
-    points: [f32; BUFFER_SIZE],
+    points: Vec<f32>,
     dirty: bool,
+    key: ChunkKey,
+    // faces next to a coarser chunk, see lod::stitch
+    transitions: u8,
+    // bumped by every density request, so results of older ones can be told apart
+    generation: u32,
 }
 
 
 impl Chunk {
-    pub fn new(points: [f32; BUFFER_SIZE], dirty: bool) -> Self { Self { points, dirty } }
+    pub fn new(points: Vec<f32>, dirty: bool, key: ChunkKey) -> Self { Self { points, dirty, key, transitions: 0, generation: 0 } }
+
+    pub fn new_empty(dims: ChunkDims, key: ChunkKey) -> Self {
+        Self {points: vec![-1.0; dims.buffer_size()], dirty: false, key, transitions: 0, generation: 0}
+    }
+
+    pub fn key(&self) -> ChunkKey {
+        self.key
+    }
+
+    // the generation to tag a new density request with, results of the ones before it get dropped
+    pub fn request_density(&mut self) -> u32 {
+        self.generation = self.generation.wrapping_add(1);
+        self.generation
+    }
+
+    // false if a newer request was made since, the readbacks don't come back in order
+    pub fn receive_density(&mut self, generation: u32, points: Vec<f32>) -> bool {
+        if generation != self.generation {return false}
+
+        self.points = points;
+        self.dirty = true;
+        true
+    }
+
+    // the samples to mesh, with the borders to coarser chunks stitched
+    pub fn mesh_points(&self, dims: ChunkDims) -> Cow<[f32]> {
+        if self.transitions == 0 {
+            return Cow::Borrowed(&self.points);
+        }
 
-    pub fn new_empty() -> Self {
-        Self {points: [-1.0; BUFFER_SIZE], dirty: false}
+        let mut points = self.points.clone();
+        lod::stitch(&mut points, dims, self.key, self.transitions);
+        Cow::Owned(points)
     }
 }
 
@@ -78,59 +150,467 @@ pub struct ChunkBundle {
     pub mesh_bundle: MaterialMeshBundle<ChunkMaterial>,
 }
 
+// a chunk whose mesh stays on the gpu, gets its GpuChunkMesh once it's meshed the first time
+#[derive(Bundle)]
+pub struct GpuChunkBundle {
+    pub chunk: Chunk,
+    pub transform: Transform,
+    pub global_transform: GlobalTransform,
+    pub visibility: Visibility,
+    pub computed_visibility: ComputedVisibility,
+}
+
+// indices into MeshData::vertices
 #[derive(Clone, Copy, Debug, Pod, Zeroable)]
 #[repr(C)]
 pub struct Triangle {
-    pub a: Vec4,
-    pub b: Vec4,
-    pub c: Vec4,
+    pub a: u32,
+    pub b: u32,
+    pub c: u32,
+}
+
+// position.w is the length of the density gradient, normal.w the occlusion, see ATTRIBUTE_GRADIENT
+// and ATTRIBUTE_OCCLUSION
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(C)]
+pub struct Vertex {
+    pub position: Vec4,
+    pub normal: Vec4,
 }
 
-pub struct ChunkPlugin;
+#[derive(Clone, Debug, Default)]
+pub struct MeshData {
+    pub vertices: Vec<Vertex>,
+    pub triangles: Vec<Triangle>,
+}
+
+impl MeshData {
+    pub fn into_mesh(self, normal_mode: NormalMode) -> Mesh {
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+
+        let positions: Vec<[f32; 3]> = self.vertices.iter().map(|x| x.position.xyz().to_array()).collect();
+
+        let normals: Vec<[f32; 3]> = match normal_mode {
+            NormalMode::Face => {
+                // shared vertices let us average the face normals around them
+                let mut normals = vec![Vec3::ZERO; positions.len()];
+                for tri in self.triangles.iter() {
+                    let (a, b, c) = (tri.a as usize, tri.b as usize, tri.c as usize);
+                    let a_pos = self.vertices[a].position.xyz();
+                    let normal = (self.vertices[b].position.xyz() - a_pos).cross(self.vertices[c].position.xyz() - a_pos);
+                    normals[a] += normal;
+                    normals[b] += normal;
+                    normals[c] += normal;
+                }
+                normals.iter().map(|x| x.normalize_or_zero().to_array()).collect()
+            }
+            NormalMode::Gradient => self.vertices.iter().map(|x| x.normal.xyz().to_array()).collect(),
+        };
+
+        let indices: Vec<u32> = self.triangles.iter().flat_map(|x| [x.a, x.b, x.c]).collect();
+        let uvs: Vec<[f32; 2]> = vec![[0.0, 0.0]; positions.len()];
+        let gradients: Vec<f32> = self.vertices.iter().map(|x| x.position.w).collect();
+        let occlusion: Vec<f32> = self.vertices.iter().map(|x| x.normal.w).collect();
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+        mesh.insert_attribute(ATTRIBUTE_GRADIENT, gradients);
+        mesh.insert_attribute(ATTRIBUTE_OCCLUSION, occlusion);
+        mesh.set_indices(Some(Indices::U32(indices)));
+
+        mesh
+    }
+}
+
+// which mesher turns dirty chunks into meshes, can be switched at runtime
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MeshBackend {
+    Gpu,
+    Cpu,
+}
+
+fn gpu_backend(backend: Res<MeshBackend>) -> ShouldRun {
+    if *backend == MeshBackend::Gpu { ShouldRun::Yes } else { ShouldRun::No }
+}
+
+fn cpu_backend(backend: Res<MeshBackend>) -> ShouldRun {
+    if *backend == MeshBackend::Cpu { ShouldRun::Yes } else { ShouldRun::No }
+}
+
+// the DensityBackend is picked by NoisePlugin, added after this one
+fn gpu_density(backend: Res<DensityBackend>) -> ShouldRun {
+    if *backend == DensityBackend::Gpu { ShouldRun::Yes } else { ShouldRun::No }
+}
+
+fn cpu_density(backend: Res<DensityBackend>) -> ShouldRun {
+    if *backend == DensityBackend::Cpu { ShouldRun::Yes } else { ShouldRun::No }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NormalMode {
+    // averaged from the faces around each vertex
+    Face,
+    // interpolated from the density gradient at the cell corners
+    Gradient,
+}
+
+// room for one chunk's mesh in the gpu buffers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MeshCapacity {
+    pub vertices: usize,
+    pub triangles: usize,
+}
+
+impl MeshCapacity {
+    // every sample could own three crossings, but real terrain stays far below that
+    pub fn new(dims: ChunkDims) -> Self {
+        let worst_case = Self::worst_case(dims);
+        Self {
+            vertices: dims.buffer_size().min(worst_case.vertices),
+            triangles: (dims.buffer_size() * 2).min(worst_case.triangles),
+        }
+    }
+
+    // enough for any density field, three crossings per sample and five triangles per cell.
+    // the asymptotic decider can add three loop centers and up to twelve triangles per cell
+    pub fn worst_case(dims: ChunkDims) -> Self {
+        Self {
+            vertices: dims.buffer_size() * 3 + dims.cells().pow(3) * 3,
+            triangles: dims.cells().pow(3) * 12,
+        }
+    }
+
+    pub fn fits(&self, vertices: u32, triangles: u32) -> bool {
+        vertices as usize <= self.vertices && triangles as usize <= self.triangles
+    }
+
+    // doubles until the counts fit, but never past the worst case
+    pub fn grow_to_fit(&self, dims: ChunkDims, vertices: u32, triangles: u32) -> Self {
+        let worst_case = Self::worst_case(dims);
+        let mut capacity = *self;
+        while !capacity.fits(vertices, triangles) && capacity != worst_case {
+            capacity.vertices = (capacity.vertices * 2).min(worst_case.vertices);
+            capacity.triangles = (capacity.triangles * 2).min(worst_case.triangles);
+        }
+        capacity
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MeshError {
+    // the chunk needs more room than the largest buffers the device allows. it keeps its old mesh,
+    // or draws nothing when it stays on the gpu
+    Overflow {
+        entity: Entity,
+        vertices: u32,
+        triangles: u32,
+        capacity: MeshCapacity,
+    },
+}
+
+impl std::fmt::Display for MeshError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MeshError::Overflow { entity, vertices, triangles, capacity } => write!(
+                f,
+                "chunk {:?} has {} vertices and {} triangles, but there is only room for {} and {}",
+                entity, vertices, triangles, capacity.vertices, capacity.triangles
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MeshError {}
+
+// meshes every loaded chunk again at the new iso level, from the density it already has
+pub struct SetIsoLevel(pub f32);
+
+pub struct ChunkSettings {
+    pub dims: ChunkDims,
+    pub normals: NormalMode,
+    pub lod_levels: u32,
+    pub unload_margin: i32,
+    pub gpu_resident: bool,
+    pub deterministic: bool,
+    pub extractor: Arc<dyn SurfaceExtractor>,
+    pub iso_level: f32,
+}
+
+pub struct ChunkPlugin {
+    pub dims: ChunkDims,
+    pub normals: NormalMode,
+    // chunks at lod n cover 2^n times the distance with the same number of samples
+    pub lod_levels: u32,
+    // how many of the coarsest chunks past the view range a chunk is kept around
+    pub unload_margin: i32,
+    // draw the march output directly instead of reading it back into a Mesh. always uses gradient
+    // normals. every chunk keeps buffers of the current MeshCapacity, which starts out well below
+    // the worst case and grows when a chunk overflows it. the mesh never comes back to the cpu,
+    // so lod::fill_transitions can't close the lod borders
+    pub gpu_resident: bool,
+    // the gpu mesher counts and scans before writing, so vertices and triangles come out in the
+    // same order every time instead of whichever invocation got to the atomics first. costs two more passes
+    pub deterministic: bool,
+    // marching cubes by default. the others only run on the cpu, gpu_resident is ignored for them
+    pub extractor: Arc<dyn SurfaceExtractor>,
+    // the density the surface lies at, can be changed later with SetIsoLevel
+    pub iso_level: f32,
+}
+
+impl Default for ChunkPlugin {
+    fn default() -> Self {
+        Self {
+            dims: ChunkDims::default(),
+            normals: NormalMode::Gradient,
+            lod_levels: 4,
+            unload_margin: 1,
+            gpu_resident: false,
+            deterministic: false,
+            extractor: Arc::new(MarchingCubes::default()),
+            iso_level: 0.0,
+        }
+    }
+}
+
+// materials of unloaded chunks, handed out again to new ones
+#[derive(Default)]
+pub struct ChunkPool {
+    materials: Vec<Handle<ChunkMaterial>>,
+    gpu_meshes: Vec<GpuChunkMesh>,
+}
+
+// every loaded chunk by its key. cameras far apart can want different lods of the same area,
+// so a coord can hold a chunk of every lod at once
+#[derive(Default)]
+pub struct ChunkMap {
+    chunks: HashMap<ChunkKey, Entity>,
+}
+
+impl ChunkMap {
+    pub fn get(&self, key: ChunkKey) -> Option<Entity> {
+        self.chunks.get(&key).copied()
+    }
+
+    pub fn contains(&self, key: ChunkKey) -> bool {
+        self.chunks.contains_key(&key)
+    }
+
+    pub fn insert(&mut self, key: ChunkKey, entity: Entity) {
+        self.chunks.insert(key, entity);
+    }
+
+    pub fn remove(&mut self, key: ChunkKey) -> Option<Entity> {
+        self.chunks.remove(&key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ChunkKey, Entity)> + '_ {
+        self.chunks.iter().map(|(key, entity)| (*key, *entity))
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    // the finest loaded chunk that covers the lod 0 chunk at coord
+    pub fn containing(&self, coord: IVec3, lod_levels: u32) -> Option<(ChunkKey, Entity)> {
+        (0..lod_levels)
+            .map(|lod| ChunkKey::new(coord, 0).ancestor(lod))
+            .find_map(|key| self.get(key).map(|entity| (key, entity)))
+    }
+
+    // the chunk across a face (-x, +x, -y, +y, -z, +z). a finer neighbour only touches part of the
+    // face, this returns the one at the face's min corner
+    pub fn neighbour(&self, key: ChunkKey, face: usize, lod_levels: u32) -> Option<(ChunkKey, Entity)> {
+        self.containing(key.across(face), lod_levels)
+    }
+
+    // bit per face that borders a coarser chunk
+    pub fn transitions(&self, key: ChunkKey, lod_levels: u32) -> u8 {
+        let mut mask = 0;
+
+        for face in 0..6 {
+            if let Some((neighbour, _)) = self.neighbour(key, face, lod_levels) {
+                if neighbour.lod > key.lod {
+                    mask |= 1 << face;
+                }
+            }
+        }
+
+        mask
+    }
+}
 
 impl Plugin for ChunkPlugin {
     fn build(&self, app: &mut App) {
+        let has_gpu = app.world.get_resource::<RenderDevice>().is_some();
+        let gpu_mesher = has_gpu && self.extractor.march_kernel().is_some();
+
+        app.insert_resource(ChunkSettings {
+            dims: self.dims,
+            normals: self.normals,
+            lod_levels: self.lod_levels.max(1),
+            unload_margin: self.unload_margin.max(0),
+            gpu_resident: self.gpu_resident && gpu_mesher,
+            deterministic: self.deterministic,
+            extractor: self.extractor.clone(),
+            iso_level: self.iso_level,
+        });
+
+        // fall back to the cpu mesher on headless setups and for extractors without a kernel
+        let backend = if gpu_mesher {
+            app.init_resource::<ChunkPipeline>();
+            if self.gpu_resident {
+                app.add_plugin(GpuMeshPlugin);
+            }
+            MeshBackend::Gpu
+        } else {
+            MeshBackend::Cpu
+        };
+
         app
             .add_plugin(MaterialPlugin::<ChunkMaterial>::default())
-            .init_resource::<ChunkPipeline>()
+            .insert_resource(backend)
             .insert_resource(ChunkSpawnTimer(Timer::from_seconds(1.0, true)))
-            .add_system_to_stage(CoreStage::PreUpdate, chunk_generation_system)
-            .add_system_to_stage(CoreStage::Update, compute_mesh)
+            .init_resource::<ChunkPool>()
+            .init_resource::<ChunkMap>()
+            .add_event::<MeshError>()
+            .add_event::<SetIsoLevel>()
+            .add_system_to_stage(CoreStage::PreUpdate, iso_level_system)
+            .add_system_to_stage(CoreStage::PreUpdate, chunk_generation_system.with_run_criteria(gpu_density))
+            .add_system_to_stage(CoreStage::PreUpdate, chunk_generation_cpu_system.with_run_criteria(cpu_density))
+            .add_system_to_stage(CoreStage::PreUpdate, assign_generated_chunks.with_run_criteria(cpu_density))
+            .add_system_to_stage(CoreStage::Update, compute_mesh.with_run_criteria(gpu_backend))
+            .add_system_to_stage(CoreStage::Update, compute_mesh_cpu.with_run_criteria(cpu_backend))
             .add_system_to_stage(CoreStage::PostUpdate, spawn_chunk_system);
     }
 }
 
 
+// buffers for BATCH_SIZE chunks, every chunk gets its own slice of each
 struct ChunkCumputeBuffers {
+    dims_buffer: Buffer,
     point_buffer: Buffer,
     atomics_buffer: Buffer,
+    vertex_buffer: Buffer,
     triangle_buffer: Buffer,
+    edge_buffer: Buffer,
+    // per sample counts, scanned into where its vertices and triangles go. only used when deterministic
+    vert_offset_buffer: Buffer,
+    tri_offset_buffer: Buffer,
+    // DrawArgs of every chunk, see GpuChunkMesh
+    draw_args_buffer: Buffer,
+    // atomics, vertices and triangles of a batch back to back, see staging_layout
+    staging: ReadbackRing<MeshBatch>,
+    capacity: MeshCapacity,
+    // the buffers get rebuilt with this once nothing is in flight anymore
+    grow_to: Option<MeshCapacity>,
+}
+
+struct MeshBatch {
+    entities: Vec<Entity>,
+    // gpu resident batches only copy the atomics, unless a chunk asked for its data
+    data: bool,
+}
+
+// index_count, instance_count, first_index, base_vertex and first_instance
+const DRAW_ARGS_SIZE: usize = std::mem::size_of::<u32>() * 5;
+
+// offsets of the vertices and triangles in a staging buffer and its total size,
+// the atomics of every chunk sit at the start
+fn staging_layout(capacity: MeshCapacity) -> (u64, u64, u64) {
+    let vertex_offset = 256;
+    let triangle_offset = vertex_offset + (std::mem::size_of::<Vertex>() * capacity.vertices * BATCH_SIZE) as u64;
+    let size = triangle_offset + (std::mem::size_of::<Triangle>() * capacity.triangles * BATCH_SIZE) as u64;
+    (vertex_offset, triangle_offset, size)
 }
 
 impl ChunkCumputeBuffers {
-    fn new_empty(render_device: &RenderDevice) -> Self{
+    fn new_empty(render_device: &RenderDevice, settings: &ChunkSettings, capacity: MeshCapacity) -> Self{
+        let dims = settings.dims;
+        let dims_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: None,
+            contents: cast_slice(&[
+                dims.axis_size as u32,
+                capacity.vertices as u32,
+                capacity.triangles as u32,
+                settings.deterministic as u32,
+                settings.iso_level.to_bits(),
+                settings.extractor.march_kernel().map_or(false, |x| x.asymptotic_decider) as u32,
+                0, 0,
+            ]),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+
         let point_buffer = render_device.create_buffer(&BufferDescriptor {
             label: None,
-            size: (std::mem::size_of::<f32>() * BUFFER_SIZE) as u64,
+            size: (std::mem::size_of::<f32>() * dims.buffer_size() * BATCH_SIZE) as u64,
             usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
 
         let atomics_buffer = render_device.create_buffer(&BufferDescriptor {
             label: None,
-            size: (std::mem::size_of::<u32>() * 1) as u64,
-            usage: BufferUsages::STORAGE | BufferUsages::MAP_READ| BufferUsages::COPY_DST,
+            size: (std::mem::size_of::<u32>() * 2 * BATCH_SIZE) as u64,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let vertex_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: (std::mem::size_of::<Vertex>() * capacity.vertices * BATCH_SIZE) as u64,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
             mapped_at_creation: false,
         });
 
         let triangle_buffer = render_device.create_buffer(&BufferDescriptor {
             label: None,
-            size: (std::mem::size_of::<Triangle>()as u64) * BUFFER_SIZE as u64 * 4,
-            usage: BufferUsages::STORAGE | BufferUsages::MAP_READ,
+            size: (std::mem::size_of::<Triangle>() * capacity.triangles * BATCH_SIZE) as u64,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
             mapped_at_creation: false,
         });
 
-        Self {point_buffer, atomics_buffer, triangle_buffer}
+        let edge_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: (std::mem::size_of::<u32>() * dims.buffer_size() * 3 * BATCH_SIZE) as u64,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let offset_size = (std::mem::size_of::<u32>() * dims.buffer_size() * BATCH_SIZE) as u64;
+        let vert_offset_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: offset_size,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let tri_offset_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: offset_size,
+            usage: BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
+        let draw_args_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: None,
+            size: (DRAW_ARGS_SIZE * BATCH_SIZE) as u64,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        let (_, _, staging_size) = staging_layout(capacity);
+        let staging = ReadbackRing::new(render_device, "chunk staging buffer", staging_size);
+
+        Self {
+            dims_buffer, point_buffer, atomics_buffer, vertex_buffer, triangle_buffer, edge_buffer,
+            vert_offset_buffer, tri_offset_buffer, draw_args_buffer, staging,
+            capacity,
+            grow_to: None,
+        }
     }
 }
 
@@ -138,7 +618,11 @@ impl ChunkCumputeBuffers {
 
 pub struct  ChunkPipeline {
     buffer_bind_group_layout: BindGroupLayout,
+    count_pipeline: ComputePipeline,
+    scan_pipeline: ComputePipeline,
+    edges_pipeline: ComputePipeline,
     march_pipeline: ComputePipeline,
+    indirect_pipeline: ComputePipeline,
 }
 
 impl FromWorld for ChunkPipeline {
@@ -187,11 +671,72 @@ impl FromWorld for ChunkPipeline {
                             min_binding_size: None
                         },
                         count: None,
-                    }   
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer { 
+                            ty: BufferBindingType::Storage { read_only: false }, 
+                            has_dynamic_offset: false, 
+                            min_binding_size: None
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer { 
+                            ty: BufferBindingType::Storage { read_only: false }, 
+                            has_dynamic_offset: false, 
+                            min_binding_size: None
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer { 
+                            ty: BufferBindingType::Uniform, 
+                            has_dynamic_offset: false, 
+                            min_binding_size: None
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer { 
+                            ty: BufferBindingType::Storage { read_only: false }, 
+                            has_dynamic_offset: false, 
+                            min_binding_size: None
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 7,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer { 
+                            ty: BufferBindingType::Storage { read_only: false }, 
+                            has_dynamic_offset: false, 
+                            min_binding_size: None
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 8,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::Buffer { 
+                            ty: BufferBindingType::Storage { read_only: false }, 
+                            has_dynamic_offset: false, 
+                            min_binding_size: None
+                        },
+                        count: None,
+                    }
                 ]
             });
 
-        let chunk_buffers = ChunkCumputeBuffers::new_empty(render_device);
+        let settings = world.get_resource::<ChunkSettings>().unwrap();
+        let chunk_buffers = ChunkCumputeBuffers::new_empty(render_device, settings, MeshCapacity::new(settings.dims));
        
         let pipeline_layout = render_device.create_pipeline_layout(&PipelineLayoutDescriptor {
             label: None,
@@ -199,33 +744,151 @@ impl FromWorld for ChunkPipeline {
             push_constant_ranges: &[],
         });
         
+        let count_pipeline = render_device.create_compute_pipeline(&RawComputePipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "count",
+        });
+
+        let scan_pipeline = render_device.create_compute_pipeline(&RawComputePipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "scan",
+        });
+
+        let edges_pipeline = render_device.create_compute_pipeline(&RawComputePipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "edges",
+        });
+
         let march_pipeline = render_device.create_compute_pipeline(&RawComputePipelineDescriptor {
             label: None,
             layout: Some(&pipeline_layout),
             module: &shader,
             entry_point: "march",
         });
+
+        let indirect_pipeline = render_device.create_compute_pipeline(&RawComputePipelineDescriptor {
+            label: None,
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "indirect",
+        });
         
         world.insert_resource(chunk_buffers);
 
         ChunkPipeline {
             buffer_bind_group_layout,
+            count_pipeline,
+            scan_pipeline,
+            edges_pipeline,
             march_pipeline,
+            indirect_pipeline,
           }
     }
 }
 
 fn compute_mesh(
+    mut commands: Commands,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
+    task_pool: Res<AsyncComputeTaskPool>,
     pipeline: Res<ChunkPipeline>,
-    chunk_buffers: Res<ChunkCumputeBuffers>,
+    mut chunk_buffers: ResMut<ChunkCumputeBuffers>,
+    mut pool: ResMut<ChunkPool>,
+    settings: Res<ChunkSettings>,
     mut meshes: ResMut<Assets<Mesh>>,
-    mut query: Query<(&mut Chunk, &Handle<Mesh>)>,
+    mut errors: EventWriter<MeshError>,
+    mut query: Query<(Entity, &mut Chunk, Option<&Handle<Mesh>>, Option<&GpuChunkMesh>, Option<&ReadbackMesh>)>,
 ) {
     let mut tri_count = 0;
+    let dims = settings.dims;
+    let workgroups = dims.workgroups();
+    let capacity = chunk_buffers.capacity;
+    let (vertex_offset, triangle_offset, _) = staging_layout(capacity);
+    let vertex_size = std::mem::size_of::<Vertex>() * capacity.vertices;
+    let triangle_size = std::mem::size_of::<Triangle>() * capacity.triangles;
 
+    // results of earlier frames
     let start = Instant::now();
+    let mut overflowed = Vec::new();
+    chunk_buffers.staging.collect(&render_device, |batch, bytes| {
+        let heads: &[u32] = cast_slice(&bytes[..8 * batch.entities.len()]);
+
+        for (i, entity) in batch.entities.into_iter().enumerate() {
+            // the chunk got unloaded in the meantime
+            let (chunk, mesh_handle, readback) = match query.get(entity) {
+                Ok((_, chunk, mesh_handle, _, readback)) => (chunk, mesh_handle, readback.is_some()),
+                Err(_) => continue,
+            };
+
+            let [vert_head, tri_head] = [heads[i * 2], heads[i * 2 + 1]];
+            // the shader dropped whatever didn't fit, so the triangles may point at missing vertices
+            if !capacity.fits(vert_head, tri_head) {
+                overflowed.push((entity, vert_head, tri_head));
+                continue;
+            }
+
+            // came along with a chunk of the same batch that asked for its data
+            if !batch.data || (settings.gpu_resident && !readback) {continue}
+
+            let start = vertex_offset as usize + i * vertex_size;
+            let range = start..start + std::mem::size_of::<Vertex>() * vert_head as usize;
+            let vertices: Vec<Vertex> = Vec::from(cast_slice(&bytes[range]));
+
+            let start = triangle_offset as usize + i * triangle_size;
+            let range = start..start + std::mem::size_of::<Triangle>() * tri_head as usize;
+            let triangles: Vec<Triangle> = Vec::from(cast_slice(&bytes[range]));
+
+            tri_count += triangles.len();
+
+            let mut data = MeshData { vertices, triangles };
+            lod::fill_transitions(&mut data, dims, chunk.key, chunk.transitions);
+            match mesh_handle.and_then(|x| meshes.get_mut(x)) {
+                Some(mesh) => *mesh = data.into_mesh(settings.normals),
+                None => {
+                    commands.entity(entity).insert(ChunkMeshData(data));
+                }
+            }
+        }
+    });
+
+    // grow the buffers and mesh the chunk again, as long as the device allows buffers that big
+    let storage_limit = render_device.limits().max_storage_buffer_binding_size as usize;
+    for (entity, vertices, triangles) in overflowed {
+        let grown = chunk_buffers.grow_to.unwrap_or(capacity).grow_to_fit(dims, vertices, triangles);
+        let vertex_bytes = std::mem::size_of::<Vertex>() * grown.vertices * BATCH_SIZE;
+        let triangle_bytes = std::mem::size_of::<Triangle>() * grown.triangles * BATCH_SIZE;
+
+        if !grown.fits(vertices, triangles) || vertex_bytes.max(triangle_bytes) > storage_limit {
+            errors.send(MeshError::Overflow { entity, vertices, triangles, capacity });
+            continue;
+        }
+
+        chunk_buffers.grow_to = Some(grown);
+        if let Ok((_, mut chunk, ..)) = query.get_mut(entity) {
+            chunk.dirty = true;
+        }
+    }
+
+    if let Some(grown) = chunk_buffers.grow_to {
+        // the staging buffers in flight still have the old layout
+        if chunk_buffers.staging.in_flight().next().is_some() {return}
+
+        *chunk_buffers = ChunkCumputeBuffers::new_empty(&render_device, &settings, grown);
+        pool.gpu_meshes.clear();
+        return;
+    }
+
+    // SetIsoLevel only marks the chunks dirty, the kernels pick the new level up from here
+    if settings.is_changed() {
+        render_queue.write_buffer(&chunk_buffers.dims_buffer, 16, cast_slice(&[settings.iso_level]));
+    }
+
     let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
         label: None,
         layout: &pipeline.buffer_bind_group_layout,
@@ -240,66 +903,179 @@ fn compute_mesh(
             },
             BindGroupEntry {
                 binding: 2,
+                resource: chunk_buffers.vertex_buffer.as_entire_binding()
+            },
+            BindGroupEntry {
+                binding: 3,
                 resource: chunk_buffers.triangle_buffer.as_entire_binding()
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: chunk_buffers.edge_buffer.as_entire_binding()
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: chunk_buffers.dims_buffer.as_entire_binding()
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: chunk_buffers.draw_args_buffer.as_entire_binding()
+            },
+            BindGroupEntry {
+                binding: 7,
+                resource: chunk_buffers.vert_offset_buffer.as_entire_binding()
+            },
+            BindGroupEntry {
+                binding: 8,
+                resource: chunk_buffers.tri_offset_buffer.as_entire_binding()
             }
         ],
     });
-    for (mut chunk, mesh_handle) in query.iter_mut() {
-        if !chunk.dirty {continue;}
-        let bytes: &[u8] = cast_slice(&chunk.points);
-        render_queue.write_buffer(&chunk_buffers.point_buffer, 0, &bytes[..]);
 
+    // an older result that's still on the way has to land first, so the meshes don't arrive out of order
+    let in_flight: Vec<Entity> = chunk_buffers.staging.in_flight().flat_map(|x| x.entities.iter()).copied().collect();
+    let mut dirty = query.iter_mut()
+        .filter(|(entity, chunk, ..)| chunk.dirty && !in_flight.contains(entity));
+
+    // the compute buffers are shared, the queue runs the submissions in order so every
+    // batch's copy still sees its own results
+    loop {
+        let slot = match chunk_buffers.staging.free_slot() {
+            Some(slot) => slot,
+            None => break,
+        };
+
+        let mut batch: Vec<_> = dirty.by_ref().take(BATCH_SIZE).collect();
+        if batch.is_empty() {break}
+
+        // meshes that stay on the gpu only need their counts back, unless someone wants their data
+        let data = !settings.gpu_resident || batch.iter().any(|(.., readback)| readback.is_some());
 
-        render_queue.write_buffer(&chunk_buffers.atomics_buffer, 0, cast_slice(&[0]));
+        for (i, (_, chunk, ..)) in batch.iter_mut().enumerate() {
+            let points = chunk.mesh_points(dims);
+            let offset = (std::mem::size_of::<f32>() * dims.buffer_size() * i) as u64;
+            render_queue.write_buffer(&chunk_buffers.point_buffer, offset, cast_slice(&points));
+
+            chunk.dirty = false;
+        }
+
+        render_queue.write_buffer(&chunk_buffers.atomics_buffer, 0, cast_slice(&vec![0u32; 2 * batch.len()]));
 
         let mut command_encoder = render_device.create_command_encoder(&CommandEncoderDescriptor { label: Some("mesh command encoder") });
+        if settings.deterministic {
+            {
+                let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor::default());
+                pass.set_pipeline(&pipeline.count_pipeline);
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.dispatch(workgroups, workgroups, workgroups * batch.len() as u32)
+            }
+            {
+                // one workgroup per chunk, it also fills in the atomics
+                let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor::default());
+                pass.set_pipeline(&pipeline.scan_pipeline);
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.dispatch(1, 1, batch.len() as u32)
+            }
+        }
+        {
+            let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            pass.set_pipeline(&pipeline.edges_pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch(workgroups, workgroups, workgroups * batch.len() as u32)
+        }
         {
             let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor::default());
             pass.set_pipeline(&pipeline.march_pipeline);
             pass.set_bind_group(0, &bind_group, &[]);
-            pass.dispatch(4, 4, 4)
+            pass.dispatch(workgroups, workgroups, workgroups * batch.len() as u32)
+        }
+
+        if settings.gpu_resident {
+            {
+                let mut pass = command_encoder.begin_compute_pass(&ComputePassDescriptor::default());
+                pass.set_pipeline(&pipeline.indirect_pipeline);
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.dispatch(1, 1, 1)
+            }
+
+            for (i, (entity, _, _, gpu_mesh, _)) in batch.iter().enumerate() {
+                let gpu_mesh = match gpu_mesh {
+                    Some(gpu_mesh) if gpu_mesh.capacity == capacity => (*gpu_mesh).clone(),
+                    // new chunk, or its buffers are from before the last growth
+                    _ => {
+                        let gpu_mesh = pool.gpu_meshes.pop()
+                            .filter(|x| x.capacity == capacity)
+                            .unwrap_or_else(|| GpuChunkMesh::new(&render_device, capacity));
+                        commands.entity(*entity).insert(gpu_mesh.clone());
+                        gpu_mesh
+                    }
+                };
+
+                command_encoder.copy_buffer_to_buffer(&chunk_buffers.vertex_buffer, (vertex_size * i) as u64, &gpu_mesh.vertex_buffer, 0, vertex_size as u64);
+                command_encoder.copy_buffer_to_buffer(&chunk_buffers.triangle_buffer, (triangle_size * i) as u64, &gpu_mesh.index_buffer, 0, triangle_size as u64);
+                command_encoder.copy_buffer_to_buffer(&chunk_buffers.draw_args_buffer, (DRAW_ARGS_SIZE * i) as u64, &gpu_mesh.indirect_buffer, 0, DRAW_ARGS_SIZE as u64);
+            }
+        }
+
+        // only the slices of the chunks in this batch
+        let count = batch.len();
+        let staging = chunk_buffers.staging.buffer(slot);
+        command_encoder.copy_buffer_to_buffer(&chunk_buffers.atomics_buffer, 0, staging, 0, (8 * count) as u64);
+        if data {
+            command_encoder.copy_buffer_to_buffer(&chunk_buffers.vertex_buffer, 0, staging, vertex_offset, (vertex_size * count) as u64);
+            command_encoder.copy_buffer_to_buffer(&chunk_buffers.triangle_buffer, 0, staging, triangle_offset, (triangle_size * count) as u64);
         }
         render_queue.submit(once(command_encoder.finish()));
 
-        let slice = &chunk_buffers.atomics_buffer.slice(..);
-        render_device.map_buffer(slice, MapMode::Read);
-        let tri_head: u32 = cast_slice(&slice.get_mapped_range()[..])[0];
-        chunk_buffers.atomics_buffer.unmap();
-        
-        let range = 0..std::mem::size_of::<Triangle>() * tri_head as usize;
-        let slice = &chunk_buffers.triangle_buffer.slice(..) ;
-        render_device.map_buffer(slice, MapMode::Read);
-        let triangles: Vec<Triangle> = Vec::from(cast_slice(&slice.get_mapped_range()[range]));
-        chunk_buffers.triangle_buffer.unmap();
-        
-        tri_count += triangles.len();
+        let entities = batch.iter().map(|(entity, ..)| *entity).collect();
+        chunk_buffers.staging.map(slot, MeshBatch { entities, data }, &task_pool);
+    }
 
-        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
-        
-        let mut vertices: Vec<[f32; 3]> = Vec::new();
-        triangles.iter().for_each(|x| {
-            vertices.append(&mut vec![
-                x.a.xyz().to_array(),
-                x.b.xyz().to_array(),
-                x.c.xyz().to_array(),
-                ]);
-            });
-            
-        let length = vertices.len() as u32;
-        let indices = (0..length as u32).collect::<Vec<u32>>();
-        let uvs: Vec<[f32; 2]> = vec![[0.0, 0.0]; length as usize];
-        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, vertices);
-        mesh.compute_flat_normals();
-        mesh.set_indices(Some(Indices::U32(indices)));
-        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    let elapsed = start.elapsed();
+    if elapsed.as_millis() < 1 {return}
+    debug!("Mesh took: {:.2?} for {} triangles", elapsed, tri_count);
+}
+
+fn iso_level_system(
+    mut events: EventReader<SetIsoLevel>,
+    mut settings: ResMut<ChunkSettings>,
+    mut chunks: Query<&mut Chunk, Without<PendingDensity>>,
+) {
+    let iso_level = match events.iter().last() {
+        Some(SetIsoLevel(iso_level)) => *iso_level,
+        None => return,
+    };
+    if iso_level == settings.iso_level {return}
+
+    settings.iso_level = iso_level;
+    for mut chunk in chunks.iter_mut() {
+        chunk.dirty = true;
+    }
+}
+
+fn compute_mesh_cpu(
+    settings: Res<ChunkSettings>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut query: Query<(&mut Chunk, &Handle<Mesh>)>,
+) {
+    let mut tri_count = 0;
+
+    let start = Instant::now();
+    for (mut chunk, mesh_handle) in query.iter_mut() {
+        if !chunk.dirty {continue;}
+        let points = chunk.mesh_points(settings.dims);
+        let mut data = settings.extractor.extract(Field::new(&points, settings.dims).with_iso_level(settings.iso_level));
+        lod::fill_transitions(&mut data, settings.dims, chunk.key, chunk.transitions);
 
-        *meshes.get_mut(mesh_handle).unwrap() = mesh;
+        tri_count += data.triangles.len();
+
+        *meshes.get_mut(mesh_handle).unwrap() = data.into_mesh(settings.normals);
         chunk.dirty = false;
     }
 
     let elapsed = start.elapsed();
     if elapsed.as_millis() < 1 {return}
-    println!("Mesh took: {:.2?} for {} triangles", elapsed, tri_count);
+    debug!("Mesh took: {:.2?} for {} triangles", elapsed, tri_count);
 }
 
 struct ChunkSpawnTimer(Timer);
@@ -307,108 +1083,185 @@ struct ChunkSpawnTimer(Timer);
 fn spawn_chunk_system(
     mut commands: Commands,
     cameras: Query<&Transform, With<Camera>>,
-    chunks: Query<&Transform, With<Chunk>>,
+    mut chunks: Query<(&mut Chunk, Option<&Handle<Mesh>>, Option<&Handle<ChunkMaterial>>, Option<&GpuChunkMesh>)>,
+    mut chunk_map: ResMut<ChunkMap>,
     mut wireframe_config: ResMut<WireframeConfig>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<ChunkMaterial>>,
+    mut pool: ResMut<ChunkPool>,
     time: Res<Time>,
     mut timer: ResMut<ChunkSpawnTimer>,
+    settings: Res<ChunkSettings>,
 ) {
     if !timer.0.tick(time.delta()).just_finished() {return}
 
-    let mut chunk_positions: Vec<Vec3> = Vec::new();
-    chunks.for_each(|x| chunk_positions.push((x.translation / (AXIS_SIZE-1) as f32).floor()));
+    let chunk_size = settings.dims.cells() as f32;
 
-    for transform in cameras.iter() {
-        let cam_position = (transform.translation / (AXIS_SIZE-1) as f32).floor();
-        let range_h: RangeInclusive<i32> = -5..=5;
-        let range_v: RangeInclusive<i32> = -2..=2;
-        
-        for x in range_h.clone() {
-            for y in range_v.clone() {
-                for z in range_h.clone() {
-                    let pos  = cam_position + Vec3::new(x as f32, y as f32, z as f32);
-                    
-                    if !chunk_positions.contains(&pos) {
-                        commands.spawn_bundle(ChunkBundle {
-                            chunk: Chunk::new_empty(),
-
-                            mesh_bundle: MaterialMeshBundle {
-                                mesh: meshes.add(Mesh::new(PrimitiveTopology::TriangleList)),
-                                transform: Transform::from_xyz((AXIS_SIZE-1) as f32  * pos.x , (AXIS_SIZE-1) as f32 * pos.y as f32, (AXIS_SIZE-1) as f32 * pos.z),
-                                material: materials.add(ChunkMaterial),
-                                ..Default::default()
-                            },
-                        })
-                        ;
-                    }
-                }
+    let selections: Vec<LodSelection> = cameras.iter()
+        .map(|x| LodSelection::new(x.translation, settings.dims, settings.lod_levels))
+        .collect();
+    if selections.is_empty() {return}
+
+    let unload: Vec<(ChunkKey, Entity)> = chunk_map.iter()
+        .filter(|(key, _)| lod::unloads(&selections, *key, settings.unload_margin))
+        .collect();
+
+    for (key, entity) in unload {
+        chunk_map.remove(key);
+        if let Ok((_, mesh, material, gpu_mesh)) = chunks.get(entity) {
+            if let Some(mesh) = mesh {
+                meshes.remove(mesh);
+            }
+            pool.materials.extend(material.cloned());
+            pool.gpu_meshes.extend(gpu_mesh.cloned());
+        }
+        commands.entity(entity).despawn();
+    }
+
+    // reserve the entities first, the stitching of every chunk depends on all of its neighbours
+    let mut spawned = Vec::new();
+    for selection in selections.iter() {
+        for key in selection.chunks.iter() {
+            if chunk_map.contains(*key) {continue}
+
+            let entity = commands.spawn().id();
+            chunk_map.insert(*key, entity);
+            spawned.push((*key, entity));
+        }
+    }
+
+    for (key, entity) in spawned.iter() {
+        let mut chunk = Chunk::new_empty(settings.dims, *key);
+        chunk.transitions = chunk_map.transitions(*key, settings.lod_levels);
+
+        let transform = Transform::from_translation(key.coord.as_vec3() * chunk_size)
+            .with_scale(Vec3::splat(key.scale() as f32));
+
+        if settings.gpu_resident {
+            commands.entity(*entity).insert_bundle(GpuChunkBundle {
+                chunk,
+                transform,
+                global_transform: GlobalTransform::default(),
+                visibility: Visibility::default(),
+                computed_visibility: ComputedVisibility::default(),
+            })
+            .insert(PendingDensity);
+            continue;
+        }
+
+        let material = pool.materials.pop().unwrap_or_else(|| materials.add(ChunkMaterial));
+
+        commands.entity(*entity).insert_bundle(ChunkBundle {
+            chunk,
+
+            mesh_bundle: MaterialMeshBundle {
+                mesh: meshes.add(Mesh::new(PrimitiveTopology::TriangleList)),
+                transform,
+                material,
+                ..Default::default()
+            },
+        })
+        .insert(PendingDensity);
+    }
+
+    // neighbours switched lod, the border has to be stitched differently
+    for (key, entity) in chunk_map.iter() {
+        if let Ok((mut chunk, ..)) = chunks.get_mut(entity) {
+            let transitions = chunk_map.transitions(key, settings.lod_levels);
+            if chunk.transitions != transitions {
+                chunk.transitions = transitions;
+                chunk.dirty = true;
             }
         }
     }
 }
 
+// chunks whose density hasn't been queued yet, on the gpu or the task pool
+#[derive(Component)]
+pub struct PendingDensity;
+
 fn chunk_generation_system(
-    mut query: Query<(&mut Chunk, &Transform), Added<Chunk>>,
+    mut chunks: Query<&mut Chunk>,
+    pending: Query<(Entity, &Transform), With<PendingDensity>>,
     pool: Res<AsyncComputeTaskPool>,
-    key: Res<Input<KeyCode>>,
-    simplex: Res<OpenSimplex>,
+    mut simplex: ResMut<OpenSimplex>,
     render_device: Res<RenderDevice>,
     render_queue: Res<RenderQueue>,
+    settings: Res<ChunkSettings>,
     mut commands: Commands
 ) {
-    // if !key.just_pressed(KeyCode::G){
-    //     return;
-    // }
-    if query.is_empty() {return}
-    let start = Instant::now();
+    simplex.collect_chunks(settings.dims, &render_device, |entity, generation, points| {
+        // unloaded before its density came back
+        if let Ok(mut chunk) = chunks.get_mut(entity) {
+            chunk.receive_density(generation, points);
+        }
+    });
 
-    for (mut chunk, transform) in query.iter_mut() {
+    let mut pending = pending.iter();
+    loop {
+        // the transform is scaled by the chunk's lod
+        let batch: Vec<(Entity, u32, Vec3, f32)> = pending.by_ref()
+            .filter_map(|(entity, transform)| {
+                let generation = chunks.get_mut(entity).ok()?.request_density();
+                Some((entity, generation, transform.translation, transform.scale.x))
+            })
+            .take(BATCH_SIZE)
+            .collect();
+        if batch.is_empty() {break}
 
-        chunk.points = simplex.compute_chunk(transform.translation, &render_device, &render_queue);
-        // println!("{:?}", chunk.points);
-        chunk.dirty = true;
-        // let simplex = simplex.clone();
-        // let transform = transform.clone();
-        // let task = pool.spawn(async move {
-        //     let mut points = [0.0f32 ;BUFFER_SIZE];
-        //     for i in 0..BUFFER_SIZE-1 {
-        //         points[i] = calc_iso(transform.translation + from_index(i).as_vec3(), &simplex);
-        //     }
-        //     Chunk {
-        //         points,
-        //         dirty: true,
-        //     }
-        // });
-        // commands.entity(entity).insert(task);
+        // the chunks stay pending and get a new generation next frame
+        if !simplex.dispatch_chunks(&batch, settings.dims, &render_device, &render_queue, &pool) {
+            break;
+        }
+        for (entity, ..) in batch.iter() {
+            commands.entity(*entity).remove::<PendingDensity>();
+        }
     }
+}
 
-    let elapsed = start.elapsed();
-    println!("gen took: {:.2?}", elapsed);
+// density being evaluated on the task pool, see chunk_generation_cpu_system
+#[derive(Component)]
+pub struct DensityTask(Task<Vec<f32>>);
+
+// the DensityBackend::Cpu version of chunk_generation_system, one task per chunk
+fn chunk_generation_cpu_system(
+    pending: Query<(Entity, &Transform), With<PendingDensity>>,
+    pool: Res<AsyncComputeTaskPool>,
+    settings: Res<ChunkSettings>,
+    graph: Res<DensityGraph>,
+    params: Res<TerrainParams>,
+    mut commands: Commands
+) {
+    if pending.is_empty() {return}
+
+    let graph = Arc::new(graph.clone());
+    let params = *params;
+    let dims = settings.dims;
+
+    for (entity, transform) in pending.iter() {
+        let graph = graph.clone();
+        let (origin, scale) = (transform.translation, transform.scale.x);
+
+        let task = pool.spawn(async move {
+            graph.eval_chunk(origin, scale, dims, &params)
+        });
+
+        // replaces the task of a chunk that got regenerated before it finished
+        commands.entity(entity)
+            .remove::<PendingDensity>()
+            .insert(DensityTask(task));
+    }
 }
 
 fn assign_generated_chunks(
     mut commands: Commands,
-    mut gen_tasks: Query<(Entity, &mut Chunk, &mut Task<Chunk>)>,
+    mut gen_tasks: Query<(Entity, &mut Chunk, &mut DensityTask)>,
 ) {
     for (entity, mut chunk, mut task) in gen_tasks.iter_mut() {
-        if let Some(new_chunk) = future::block_on(future::poll_once(&mut *task)) {
-            chunk.points = new_chunk.points;
-            chunk.dirty = new_chunk.dirty;
-            commands.entity(entity).remove::<Task<Chunk>>();
+        if let Some(points) = future::block_on(future::poll_once(&mut task.0)) {
+            chunk.points = points;
+            chunk.dirty = true;
+            commands.entity(entity).remove::<DensityTask>();
         }
     }
 }
-
-fn calc_iso(ws: Vec3, simplex: &OpenSimplexNoise) -> f32{
-    let mut density = -ws.y;
-
-    let mut freq = 0.015;
-    let mut amplitude = 10.0;
-    for _ in 0..=9 {
-        density += (simplex.eval_3d(ws.x as f64 * freq, ws.y as f64 * freq, ws.z as f64 * freq) as f32 + 1.0) * amplitude;
-        freq *= 2.0;
-        amplitude *= 0.5;
-    }
-    density
-}
\ No newline at end of file
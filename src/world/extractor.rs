@@ -0,0 +1,269 @@
+use bevy::{prelude::*, utils::HashMap};
+
+use super::{
+    chunk::{MeshData, Triangle, Vertex, PADDING},
+    mesher::{self, Field},
+    tables::*,
+};
+
+// turns the density samples of a chunk into a mesh, picked per ChunkPlugin.
+// triangles are counter clockwise seen from outside, like the ones of the march kernel
+pub trait SurfaceExtractor: Send + Sync + 'static {
+    fn extract(&self, field: Field) -> MeshData;
+
+    // the settings marchig_cubes.wgsl builds the same mesh with, every other extractor runs on the cpu
+    fn march_kernel(&self) -> Option<&MarchingCubes> {
+        None
+    }
+}
+
+// the default, smooth surfaces that round off sharp features
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MarchingCubes {
+    // the classic table splits ambiguous faces by the signs of their corners alone, which can
+    // disagree with the field in between. this resolves them with the asymptotic decider and
+    // traces the loops on the faces instead, the mesh stays closed and manifold either way.
+    // costs up to twelve instead of five triangles per cell
+    pub asymptotic_decider: bool,
+}
+
+impl SurfaceExtractor for MarchingCubes {
+    fn extract(&self, field: Field) -> MeshData {
+        mesher::march(field, self.asymptotic_decider)
+    }
+
+    fn march_kernel(&self) -> Option<&MarchingCubes> {
+        Some(self)
+    }
+}
+
+// every cell split into six tetrahedra around the diagonal from corner 0 to corner 6. no ambiguous
+// cases, but about twice the triangles of marching cubes
+pub struct MarchingTetrahedra;
+
+// corners of the tetrahedra, indices into CORNER_OFFSETS
+const TETRAHEDRA: [[usize; 4]; 6] = [
+    [0, 6, 1, 2],
+    [0, 6, 2, 3],
+    [0, 6, 3, 7],
+    [0, 6, 7, 4],
+    [0, 6, 4, 5],
+    [0, 6, 5, 1],
+];
+
+impl SurfaceExtractor for MarchingTetrahedra {
+    fn extract(&self, field: Field) -> MeshData {
+        let mut data = MeshData::default();
+        // crossings are shared between tetrahedra by the samples they lie between
+        let mut crossings: HashMap<(usize, usize), u32> = HashMap::default();
+
+        let mut crossing = |data: &mut MeshData, a: IVec3, b: IVec3| {
+            let key = {
+                let (a, b) = (field.dims.to_index(a), field.dims.to_index(b));
+                (a.min(b), a.max(b))
+            };
+            *crossings.entry(key).or_insert_with(|| {
+                data.vertices.push(field.crossing_vertex(a, b));
+                data.vertices.len() as u32 - 1
+            })
+        };
+
+        for y in field.first()..field.last() {
+            for z in field.first()..field.last() {
+                for x in field.first()..field.last() {
+                    let id = IVec3::new(x, y, z);
+
+                    for tetrahedron in TETRAHEDRA {
+                        let corners = tetrahedron.map(|x| id + IVec3::from(CORNER_OFFSETS[x]));
+                        let (inside, outside): (Vec<IVec3>, Vec<IVec3>) = corners.iter()
+                            .partition(|x| field.density(**x) >= 0.0);
+
+                        // wound by the order of the corners alone, so neighbouring tetrahedra always agree.
+                        // with a positive volume(a, b, c, d), b, c, d go counter clockwise seen from away from a
+                        let polygon = match inside.len() {
+                            1 => {
+                                let [j, k, l] = [outside[0], outside[1], outside[2]];
+                                let (k, l) = if volume(inside[0], j, k, l) > 0 { (k, l) } else { (l, k) };
+                                [j, k, l].iter().map(|x| crossing(&mut data, inside[0], *x)).collect::<Vec<_>>()
+                            }
+                            3 => {
+                                let [a, b, c] = [inside[0], inside[1], inside[2]];
+                                let (b, c) = if volume(outside[0], a, b, c) > 0 { (c, b) } else { (b, c) };
+                                [a, b, c].iter().map(|x| crossing(&mut data, *x, outside[0])).collect()
+                            }
+                            // the crossings of two inside and two outside corners, in order around the quad
+                            2 => {
+                                let (a, b) = (inside[0], inside[1]);
+                                let (c, d) = if volume(a, b, outside[0], outside[1]) > 0 {
+                                    (outside[0], outside[1])
+                                } else {
+                                    (outside[1], outside[0])
+                                };
+                                vec![
+                                    crossing(&mut data, a, c),
+                                    crossing(&mut data, a, d),
+                                    crossing(&mut data, b, d),
+                                    crossing(&mut data, b, c),
+                                ]
+                            }
+                            _ => continue,
+                        };
+
+                        for i in 1..polygon.len() - 1 {
+                            data.triangles.push(Triangle { a: polygon[0], b: polygon[i], c: polygon[i + 1] });
+                        }
+                    }
+                }
+            }
+        }
+
+        data
+    }
+}
+
+// one vertex per cell and sheet of the surface in it, put where the planes given by the crossings
+// and their gradients meet best. keeps the corners and edges marching cubes cuts off.
+// closed but not always manifold, a tunnel through a cell face gets pinched, see dual
+pub struct DualContouring {
+    // pulls the vertex towards the mean of the crossings, keeps flat and nearly flat cells stable
+    pub bias: f32,
+}
+
+impl Default for DualContouring {
+    fn default() -> Self {
+        Self { bias: 0.05 }
+    }
+}
+
+impl SurfaceExtractor for DualContouring {
+    fn extract(&self, field: Field) -> MeshData {
+        dual(field, |crossings| {
+            let mass_point = mass_point(crossings);
+
+            // least squares over the distances to every crossing's plane, solved around the mass point
+            let mut ata = Mat3::from_diagonal(Vec3::splat(self.bias));
+            let mut atb = Vec3::ZERO;
+            for crossing in crossings {
+                let normal = crossing.normal.truncate();
+                let position = crossing.position.truncate() - mass_point;
+                ata += Mat3::from_cols(normal * normal.x, normal * normal.y, normal * normal.z);
+                atb += normal * normal.dot(position);
+            }
+
+            let offset = ata.inverse() * atb;
+            if offset.is_finite() { mass_point + offset } else { mass_point }
+        })
+    }
+}
+
+// one vertex per cell and sheet at the mean of its crossings. no slivers, cheap enough for
+// distant chunks and collision proxies. closed but not always manifold, same as DualContouring
+pub struct SurfaceNets;
+
+impl SurfaceExtractor for SurfaceNets {
+    fn extract(&self, field: Field) -> MeshData {
+        dual(field, mass_point)
+    }
+}
+
+fn mass_point(crossings: &[Vertex]) -> Vec3 {
+    crossings.iter().fold(Vec3::ZERO, |sum, x| sum + x.position.truncate()) / crossings.len() as f32
+}
+
+// shared by the dual extractors: place picks a vertex from the crossings on a cell's edges, then
+// every crossing gets a quad between the vertices of the four cells around it. a cell gets one
+// vertex per loop of mesher::face_loops, so two sheets of the surface passing through the same cell
+// stay apart instead of getting pinched into one vertex, and neighbours agree on which is which.
+// this isn't manifold everywhere: a sheet that tunnels through a face, crossing it twice in both cells,
+// still pinches the edge between their vertices into four triangles. splitting those needs the
+// cells on both sides of the face, which the chunk next door doesn't have for the apron cells.
+// the mesh stays closed, rare in smooth terrain and common in noise finer than a cell.
+// cells one sample into the apron take part, so the quads on the faces close the gap to the next chunk
+pub(super) fn dual(field: Field, place: impl Fn(&[Vertex]) -> Vec3) -> MeshData {
+    let mut data = MeshData::default();
+    // the vertex of every edge of a cell that has a crossing
+    let mut cell_vertices: HashMap<IVec3, [u32; 12]> = HashMap::default();
+
+    let first = field.first();
+    let last = field.last();
+
+    for y in first - 1..last {
+        for z in first - 1..last {
+            for x in first - 1..last {
+                let id = IVec3::new(x, y, z);
+                let mut vertices = [u32::MAX; 12];
+
+                for cell_loop in mesher::face_loops(CORNER_OFFSETS.map(|x| field.density(id + IVec3::from(x)))) {
+                    let crossings: Vec<Vertex> = cell_loop.iter()
+                        .map(|edge| {
+                            let a = id + IVec3::from(EDGE_ORIGIN[*edge]);
+                            field.crossing_vertex(a, a + IVec3::from(AXIS_OFFSETS[EDGE_AXIS[*edge]]))
+                        })
+                        .collect();
+
+                    // keep the vertex in its cell, the neighbours would overlap otherwise
+                    let min = id.as_vec3() - PADDING as f32;
+                    let position = place(&crossings).clamp(min, min + Vec3::ONE);
+
+                    let count = crossings.len() as f32;
+                    let gradient = crossings.iter().map(|x| x.position.w).sum::<f32>() / count;
+                    let occlusion = crossings.iter().map(|x| x.normal.w).sum::<f32>() / count;
+                    let normal = crossings.iter().fold(Vec3::ZERO, |sum, x| sum + x.normal.truncate()).normalize_or_zero();
+
+                    for edge in cell_loop {
+                        vertices[edge] = data.vertices.len() as u32;
+                    }
+                    data.vertices.push(Vertex {
+                        position: position.extend(gradient),
+                        normal: normal.extend(occlusion),
+                    });
+                }
+
+                if vertices.iter().any(|x| *x != u32::MAX) {
+                    cell_vertices.insert(id, vertices);
+                }
+            }
+        }
+    }
+
+    // the edges of the samples this chunk owns, the ones on the last face belong to the next chunk
+    for y in first..last {
+        for z in first..last {
+            for x in first..last {
+                let id = IVec3::new(x, y, z);
+
+                for (axis, offset) in AXIS_OFFSETS.iter().enumerate() {
+                    let inside = field.density(id) >= 0.0;
+                    if inside == (field.density(id + IVec3::from(*offset)) >= 0.0) {
+                        continue;
+                    }
+
+                    let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+                    let (mut du, mut dv) = (IVec3::ZERO, IVec3::ZERO);
+                    du[u] = 1;
+                    dv[v] = 1;
+
+                    // counter clockwise seen from the positive end of the edge
+                    let quad = [id - du - dv, id - dv, id, id - du].map(|x| cell_vertices[&x][cell_edge(axis, id - x)]);
+                    let [a, b, c, d] = if inside { quad } else { [quad[3], quad[2], quad[1], quad[0]] };
+
+                    data.triangles.push(Triangle { a, b, c });
+                    data.triangles.push(Triangle { a, b: c, c: d });
+                }
+            }
+        }
+    }
+
+    data
+}
+
+// the edge of a cell along axis that starts at origin, relative to the cell
+fn cell_edge(axis: usize, origin: IVec3) -> usize {
+    (0..12).find(|x| EDGE_AXIS[*x] == axis && IVec3::from(EDGE_ORIGIN[*x]) == origin).unwrap()
+}
+
+// six times the signed volume of the tetrahedron
+fn volume(a: IVec3, b: IVec3, c: IVec3, d: IVec3) -> i32 {
+    let (b, c, d) = (b - a, c - a, d - a);
+    b.x * (c.y * d.z - c.z * d.y) - b.y * (c.x * d.z - c.z * d.x) + b.z * (c.x * d.y - c.y * d.x)
+}
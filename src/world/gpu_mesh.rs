@@ -0,0 +1,260 @@
+use bevy::{
+    core_pipeline::Opaque3d,
+    ecs::system::{lifetimeless::*, SystemParamItem},
+    pbr::{MeshPipeline, MeshPipelineKey, MeshUniform, SetMeshBindGroup, SetMeshViewBindGroup},
+    prelude::*,
+    render::{
+        render_phase::{
+            AddRenderCommand, DrawFunctions, EntityRenderCommand, RenderCommandResult, RenderPhase,
+            SetItemPipeline, TrackedRenderPass,
+        },
+        render_resource::*,
+        renderer::RenderDevice,
+        texture::BevyDefault,
+        view::{ExtractedView, Msaa},
+        RenderApp, RenderStage,
+    },
+};
+
+use super::chunk::{MeshCapacity, MeshData, Triangle, Vertex};
+
+// the march output of a chunk, drawn as is with the triangle count the gpu wrote into indirect_buffer
+#[derive(Component, Clone)]
+pub struct GpuChunkMesh {
+    pub vertex_buffer: Buffer,
+    pub index_buffer: Buffer,
+    pub indirect_buffer: Buffer,
+    pub capacity: MeshCapacity,
+}
+
+impl GpuChunkMesh {
+    // room for as much as a chunk's slice of the batched buffers can hold
+    pub fn new(render_device: &RenderDevice, capacity: MeshCapacity) -> Self {
+        let vertex_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("chunk vertex buffer"),
+            size: (std::mem::size_of::<Vertex>() * capacity.vertices) as u64,
+            usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        // a Triangle is three u32 indices
+        let index_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("chunk index buffer"),
+            size: (std::mem::size_of::<Triangle>() * capacity.triangles) as u64,
+            usage: BufferUsages::INDEX | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let indirect_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("chunk indirect buffer"),
+            size: (std::mem::size_of::<u32>() * 5) as u64,
+            usage: BufferUsages::INDIRECT | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Self { vertex_buffer, index_buffer, indirect_buffer, capacity }
+    }
+}
+
+// add to a chunk whose mesh stays on the gpu to also get a ChunkMeshData copy, e.g. for physics
+#[derive(Component)]
+pub struct ReadbackMesh;
+
+#[derive(Component, Default)]
+pub struct ChunkMeshData(pub MeshData);
+
+// draws every GpuChunkMesh, added by ChunkPlugin when gpu_resident is set
+pub struct GpuMeshPlugin;
+
+impl Plugin for GpuMeshPlugin {
+    fn build(&self, app: &mut App) {
+        app.sub_app_mut(RenderApp)
+            .add_render_command::<Opaque3d, DrawGpuChunk>()
+            .init_resource::<GpuChunkPipeline>()
+            .init_resource::<SpecializedRenderPipelines<GpuChunkPipeline>>()
+            .add_system_to_stage(RenderStage::Extract, extract_gpu_chunks)
+            .add_system_to_stage(RenderStage::Queue, queue_gpu_chunks);
+    }
+}
+
+// chunks have no Handle<Mesh>, so the mesh uniform the mesh bind group needs is built here
+fn extract_gpu_chunks(
+    mut commands: Commands,
+    mut previous_len: Local<usize>,
+    chunks: Query<(Entity, &ComputedVisibility, &GlobalTransform, &GpuChunkMesh)>,
+) {
+    let mut values = Vec::with_capacity(*previous_len);
+    for (entity, computed_visibility, transform, mesh) in chunks.iter() {
+        if !computed_visibility.is_visible {
+            continue;
+        }
+
+        let transform = transform.compute_matrix();
+        values.push((
+            entity,
+            (
+                mesh.clone(),
+                MeshUniform {
+                    // MeshFlags::SHADOW_RECEIVER, the flags aren't public
+                    flags: 1,
+                    transform,
+                    inverse_transpose_model: transform.inverse().transpose(),
+                },
+            ),
+        ));
+    }
+    *previous_len = values.len();
+    commands.insert_or_spawn_batch(values);
+}
+
+fn queue_gpu_chunks(
+    opaque_3d_draw_functions: Res<DrawFunctions<Opaque3d>>,
+    chunk_pipeline: Res<GpuChunkPipeline>,
+    msaa: Res<Msaa>,
+    mut pipelines: ResMut<SpecializedRenderPipelines<GpuChunkPipeline>>,
+    mut pipeline_cache: ResMut<PipelineCache>,
+    chunks: Query<(Entity, &MeshUniform), With<GpuChunkMesh>>,
+    mut views: Query<(&ExtractedView, &mut RenderPhase<Opaque3d>)>,
+) {
+    let draw_chunk = opaque_3d_draw_functions
+        .read()
+        .get_id::<DrawGpuChunk>()
+        .unwrap();
+
+    let key = MeshPipelineKey::from_msaa_samples(msaa.samples);
+    let pipeline = pipelines.specialize(&mut pipeline_cache, &chunk_pipeline, key);
+
+    for (view, mut opaque_phase) in views.iter_mut() {
+        let view_matrix = view.transform.compute_matrix();
+        let view_row_2 = view_matrix.row(2);
+        for (entity, mesh_uniform) in chunks.iter() {
+            opaque_phase.add(Opaque3d {
+                entity,
+                pipeline,
+                draw_function: draw_chunk,
+                distance: view_row_2.dot(mesh_uniform.transform.col(3)),
+            });
+        }
+    }
+}
+
+pub struct GpuChunkPipeline {
+    shader: Handle<Shader>,
+    mesh_pipeline: MeshPipeline,
+}
+
+impl FromWorld for GpuChunkPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let world = world.cell();
+        let asset_server = world.get_resource::<AssetServer>().unwrap();
+        let shader = asset_server.load("shaders/gpu_chunk.wgsl");
+
+        let mesh_pipeline = world.get_resource::<MeshPipeline>().unwrap();
+
+        GpuChunkPipeline {
+            shader,
+            mesh_pipeline: mesh_pipeline.clone(),
+        }
+    }
+}
+
+impl SpecializedRenderPipeline for GpuChunkPipeline {
+    type Key = MeshPipelineKey;
+
+    fn specialize(&self, key: Self::Key) -> RenderPipelineDescriptor {
+        RenderPipelineDescriptor {
+            label: Some("gpu chunk pipeline".into()),
+            layout: Some(vec![
+                self.mesh_pipeline.view_layout.clone(),
+                self.mesh_pipeline.mesh_layout.clone(),
+            ]),
+            vertex: VertexState {
+                shader: self.shader.clone(),
+                shader_defs: vec![],
+                entry_point: "vertex".into(),
+                buffers: vec![VertexBufferLayout {
+                    array_stride: std::mem::size_of::<Vertex>() as u64,
+                    step_mode: VertexStepMode::Vertex,
+                    attributes: vec![
+                        VertexAttribute {
+                            format: VertexFormat::Float32x4,
+                            offset: 0,
+                            shader_location: 0,
+                        },
+                        VertexAttribute {
+                            format: VertexFormat::Float32x4,
+                            offset: VertexFormat::Float32x4.size(),
+                            shader_location: 1,
+                        },
+                    ],
+                }],
+            },
+            fragment: Some(FragmentState {
+                shader: self.shader.clone(),
+                shader_defs: vec![],
+                entry_point: "fragment".into(),
+                targets: vec![ColorTargetState {
+                    format: TextureFormat::bevy_default(),
+                    blend: Some(BlendState::REPLACE),
+                    write_mask: ColorWrites::ALL,
+                }],
+            }),
+            primitive: PrimitiveState {
+                topology: PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: FrontFace::Ccw,
+                cull_mode: Some(Face::Back),
+                unclipped_depth: false,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+            },
+            depth_stencil: Some(DepthStencilState {
+                format: TextureFormat::Depth32Float,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::Greater,
+                stencil: StencilState {
+                    front: StencilFaceState::IGNORE,
+                    back: StencilFaceState::IGNORE,
+                    read_mask: 0,
+                    write_mask: 0,
+                },
+                bias: DepthBiasState {
+                    constant: 0,
+                    slope_scale: 0.0,
+                    clamp: 0.0,
+                },
+            }),
+            multisample: MultisampleState {
+                count: key.msaa_samples(),
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+        }
+    }
+}
+
+type DrawGpuChunk = (
+    SetItemPipeline,
+    SetMeshViewBindGroup<0>,
+    SetMeshBindGroup<1>,
+    DrawIndirect,
+);
+
+pub struct DrawIndirect;
+impl EntityRenderCommand for DrawIndirect {
+    type Param = SQuery<Read<GpuChunkMesh>>;
+    #[inline]
+    fn render<'w>(
+        _view: Entity,
+        item: Entity,
+        chunk_query: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let mesh = chunk_query.get_inner(item).unwrap();
+
+        pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        pass.set_index_buffer(mesh.index_buffer.slice(..), 0, IndexFormat::Uint32);
+        pass.draw_indexed_indirect(&mesh.indirect_buffer, 0);
+        RenderCommandResult::Success
+    }
+}
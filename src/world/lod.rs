@@ -0,0 +1,244 @@
+use bevy::{prelude::*, utils::{HashMap, HashSet}};
+
+use super::chunk::{ChunkDims, MeshData, Triangle, PADDING};
+
+// view range in chunks of the coarsest lod
+pub const VIEW_RANGE_H: i32 = 2;
+pub const VIEW_RANGE_V: i32 = 1;
+
+// a chunk gets split into 8 finer ones while the camera is closer than this many chunk widths,
+// which also keeps neighbouring chunks at most one lod apart
+pub const SPLIT_DISTANCE: f32 = 2.0;
+
+// coord is the min corner in lod 0 chunks, so a chunk at lod n covers 2^n of them along each axis
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ChunkKey {
+    pub coord: IVec3,
+    pub lod: u32,
+}
+
+impl ChunkKey {
+    pub fn new(coord: IVec3, lod: u32) -> Self {
+        Self { coord, lod }
+    }
+
+    pub fn scale(&self) -> i32 {
+        1 << self.lod
+    }
+
+    // the chunk at the given (coarser) lod this one lies in
+    pub fn ancestor(&self, lod: u32) -> ChunkKey {
+        let scale = 1 << lod;
+        let coord = IVec3::new(
+            self.coord.x.div_euclid(scale),
+            self.coord.y.div_euclid(scale),
+            self.coord.z.div_euclid(scale),
+        ) * scale;
+        ChunkKey::new(coord, lod)
+    }
+
+    // lod 0 coord of the first chunk across the given face (-x, +x, -y, +y, -z, +z)
+    pub fn across(&self, face: usize) -> IVec3 {
+        let mut across = self.coord;
+        across[face / 2] += if face % 2 == 0 { -1 } else { self.scale() };
+        across
+    }
+}
+
+// the chunks the camera should see, as the leaves of an octree over the coarsest chunks around it
+pub struct LodSelection {
+    pub chunks: HashSet<ChunkKey>,
+    pub roots: HashSet<ChunkKey>,
+    pub lod_levels: u32,
+    // camera position in chunks of the coarsest lod
+    center: IVec3,
+}
+
+impl LodSelection {
+    pub fn new(camera: Vec3, dims: ChunkDims, lod_levels: u32) -> Self {
+        let cells = dims.cells() as f32;
+        let top = lod_levels - 1;
+        let top_scale = (1 << top) as f32;
+        let cam = (camera / (cells * top_scale)).floor().as_ivec3();
+
+        let mut selection = Self {
+            chunks: HashSet::default(),
+            roots: HashSet::default(),
+            lod_levels,
+            center: cam,
+        };
+
+        for x in -VIEW_RANGE_H..=VIEW_RANGE_H {
+            for y in -VIEW_RANGE_V..=VIEW_RANGE_V {
+                for z in -VIEW_RANGE_H..=VIEW_RANGE_H {
+                    let root = ChunkKey::new((cam + IVec3::new(x, y, z)) * (1 << top), top);
+                    selection.roots.insert(root);
+                    selection.split(root, camera, cells);
+                }
+            }
+        }
+
+        selection
+    }
+
+    fn split(&mut self, key: ChunkKey, camera: Vec3, cells: f32) {
+        let size = cells * key.scale() as f32;
+        let min = key.coord.as_vec3() * cells;
+        let closest = camera.clamp(min, min + Vec3::splat(size));
+
+        if key.lod == 0 || closest.distance(camera) >= SPLIT_DISTANCE * size {
+            self.chunks.insert(key);
+            return;
+        }
+
+        let half = key.scale() / 2;
+        for i in 0..8 {
+            let offset = IVec3::new(i & 1, (i >> 1) & 1, (i >> 2) & 1) * half;
+            self.split(ChunkKey::new(key.coord + offset, key.lod - 1), camera, cells);
+        }
+    }
+
+    // chunks inside the selected area that aren't part of it have been replaced by another lod
+    pub fn replaces(&self, key: ChunkKey) -> bool {
+        !self.chunks.contains(&key) && self.roots.contains(&key.ancestor(self.lod_levels - 1))
+    }
+
+    // the unload range is the view range plus a margin, so chunks don't flicker in and out
+    // while the camera moves back and forth over a chunk border
+    pub fn out_of_range(&self, key: ChunkKey, margin: i32) -> bool {
+        let root = key.ancestor(self.lod_levels - 1);
+        let distance = (root.coord / root.scale() - self.center).abs();
+
+        distance.x > VIEW_RANGE_H + margin || distance.z > VIEW_RANGE_H + margin || distance.y > VIEW_RANGE_V + margin
+    }
+}
+
+// a loaded chunk can go once no camera wants it anymore and it either got replaced by another lod
+// or is too far away from every camera
+pub fn unloads(selections: &[LodSelection], key: ChunkKey, margin: i32) -> bool {
+    !selections.iter().any(|x| x.chunks.contains(&key))
+        && (selections.iter().any(|x| x.replaces(key)) || selections.iter().all(|x| x.out_of_range(key, margin)))
+}
+
+// moves the samples on faces next to a coarser chunk onto the coarse lattice, so every crossing
+// on a coarse edge lands exactly where the coarse chunk puts its vertex. samples that the coarse
+// chunk has too stay untouched, the others get interpolated from them.
+// inside a coarse face cell the fine mesh still bends through the middle samples where the coarse
+// one cuts straight across, fill_transitions closes that gap after meshing
+pub fn stitch(points: &mut [f32], dims: ChunkDims, key: ChunkKey, transitions: u8) {
+    let first = PADDING as i32;
+    let last = (dims.axis_size - 1 - PADDING) as i32;
+
+    // first mesh sample in units of this lod, tells which samples sit on the coarse lattice
+    let origin = key.coord / key.scale() * dims.cells() as i32;
+
+    for face in 0..6 {
+        if transitions & (1 << face) == 0 {
+            continue;
+        }
+
+        let axis = face / 2;
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let (mut du, mut dv) = (IVec3::ZERO, IVec3::ZERO);
+        du[u] = 1;
+        dv[v] = 1;
+
+        for a in first..=last {
+            for b in first..=last {
+                let mut pos = IVec3::ZERO;
+                pos[axis] = if face % 2 == 0 { first } else { last };
+                pos[u] = a;
+                pos[v] = b;
+
+                let odd_u = (origin[u] + a - first) & 1 == 1;
+                let odd_v = (origin[v] + b - first) & 1 == 1;
+
+                let sample = |pos: IVec3| points[dims.to_index(pos)];
+                let value = match (odd_u, odd_v) {
+                    (false, false) => continue,
+                    (true, false) => (sample(pos - du) + sample(pos + du)) * 0.5,
+                    (false, true) => (sample(pos - dv) + sample(pos + dv)) * 0.5,
+                    (true, true) => (sample(pos - du - dv) + sample(pos + du - dv) + sample(pos - du + dv) + sample(pos + du + dv)) * 0.25,
+                };
+                points[dims.to_index(pos)] = value;
+            }
+        }
+    }
+}
+
+// the transition triangles between a mesh of stitched samples and its coarser neighbours. inside
+// every coarse face cell the open border of the fine mesh runs from one crossing on a coarse edge
+// to another through crossings on the fine edges in between, those get fanned down to the straight
+// edge the coarse mesh has there. the triangles lie in the face and reuse the border vertices.
+// needs an even number of cells, so the chunk's own borders lie on the coarse lattice. borders that
+// aren't in the face, like the ones of the dual extractors, are left alone, and on an ambiguous
+// coarse face the two sides can still pair the crossings up differently
+pub fn fill_transitions(data: &mut MeshData, dims: ChunkDims, key: ChunkKey, transitions: u8) {
+    if transitions == 0 {return}
+
+    let cells = dims.cells() as f32;
+    let origin = key.coord / key.scale() * dims.cells() as i32;
+
+    let mut edges = HashSet::default();
+    for tri in data.triangles.iter() {
+        edges.extend([(tri.a, tri.b), (tri.b, tri.c), (tri.c, tri.a)]);
+    }
+
+    for face in 0..6 {
+        if transitions & (1 << face) == 0 {
+            continue;
+        }
+
+        let axis = face / 2;
+        let plane = if face % 2 == 0 { 0.0 } else { cells };
+        let position = |x: u32| data.vertices[x as usize].position.truncate();
+
+        // crossings on a coarse edge, the only ones the coarse mesh has too
+        let coarse = |x: u32| {
+            let pos = position(x);
+            [(axis + 1) % 3, (axis + 2) % 3].iter()
+                .any(|u| pos[*u].fract() == 0.0 && (origin[*u] + pos[*u] as i32) & 1 == 0)
+        };
+
+        // the open border in this face, every vertex on it has one edge leaving it
+        let next: HashMap<u32, u32> = edges.iter()
+            .filter(|(a, b)| !edges.contains(&(*b, *a)) && position(*a)[axis] == plane && position(*b)[axis] == plane)
+            .copied()
+            .collect();
+
+        let mut visited = HashSet::default();
+        let mut fans = Vec::new();
+
+        // from a vertex along the border until it gets to a coarse edge or back to the start
+        let walk = |start: u32, visited: &mut HashSet<u32>| {
+            let mut chain = vec![start];
+            let mut current = next.get(&start).copied();
+            while let Some(x) = current {
+                if x == start {break}
+                chain.push(x);
+                if coarse(x) {break}
+                visited.insert(x);
+                current = next.get(&x).copied();
+            }
+            chain
+        };
+
+        for start in next.keys().copied().filter(|x| coarse(*x)) {
+            fans.push(walk(start, &mut visited));
+        }
+
+        // loops that never touch a coarse edge, the coarse mesh doesn't have them at all
+        for start in next.keys().copied().filter(|x| !coarse(*x)) {
+            if visited.insert(start) {
+                fans.push(walk(start, &mut visited));
+            }
+        }
+
+        // walks every edge of the chain backwards, leaving the one from its last vertex to the first open
+        for chain in fans {
+            for i in 1..chain.len().saturating_sub(1) {
+                data.triangles.push(Triangle { a: chain[0], b: chain[i + 1], c: chain[i] });
+            }
+        }
+    }
+}
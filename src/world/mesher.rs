@@ -0,0 +1,274 @@
+use bevy::prelude::*;
+
+use super::{
+    chunk::{ChunkDims, MeshData, Triangle, Vertex, PADDING},
+    tables::*,
+};
+
+// cpu version of the edges and march kernels in marchig_cubes.wgsl, used when there is no gpu around
+
+// the density samples of one chunk together with their layout
+#[derive(Clone, Copy)]
+pub struct Field<'a> {
+    pub points: &'a [f32],
+    pub dims: ChunkDims,
+    // the surface lies where the density equals this
+    pub iso_level: f32,
+}
+
+impl<'a> Field<'a> {
+    pub fn new(points: &'a [f32], dims: ChunkDims) -> Self {
+        Self { points, dims, iso_level: 0.0 }
+    }
+
+    pub fn with_iso_level(self, iso_level: f32) -> Self {
+        Self { iso_level, ..self }
+    }
+
+    // relative to the iso level, so everything else can look for the surface at 0
+    pub fn density(&self, pos: IVec3) -> f32 {
+        self.points[self.dims.to_index(pos)] - self.iso_level
+    }
+
+    pub fn to_corner(&self, pos: IVec3) -> Vec4 {
+        pos.as_vec3().extend(self.density(pos))
+    }
+
+    // central difference of the density field, points towards the inside of the surface.
+    // one sided on the outermost samples, only the dual extractors get that far
+    pub fn gradient(&self, pos: IVec3) -> Vec3 {
+        let mut gradient = Vec3::ZERO;
+        for (axis, offset) in AXIS_OFFSETS.iter().enumerate() {
+            let offset = IVec3::from(*offset);
+            let (low, high) = (self.clamp(pos - offset), self.clamp(pos + offset));
+            gradient[axis] = (self.density(high) - self.density(low)) / (high[axis] - low[axis]) as f32;
+        }
+        gradient
+    }
+
+    fn clamp(&self, pos: IVec3) -> IVec3 {
+        pos.max(IVec3::ZERO).min(IVec3::splat(self.dims.axis_size as i32 - 1))
+    }
+
+    // 1 on a flat surface, less in creases and holes. counts the solid samples around the
+    // corner inside the surface, half of them being solid is as open as it gets
+    pub fn occlusion(&self, pos: IVec3) -> f32 {
+        let mut solid = 0.0;
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    if self.density(self.clamp(pos + IVec3::new(x, y, z))) >= 0.0 {
+                        solid += 1.0;
+                    }
+                }
+            }
+        }
+
+        (2.0 - solid / 13.5_f32).clamp(0.0, 1.0)
+    }
+
+    // first and last sample that ends up in the mesh, the apron only feeds the gradient
+    pub fn first(&self) -> i32 {
+        PADDING as i32
+    }
+
+    pub fn last(&self) -> i32 {
+        (self.dims.axis_size - 1 - PADDING) as i32
+    }
+
+    fn to_edge(&self, pos: IVec3, axis: usize) -> usize {
+        self.dims.to_index(pos) * 3 + axis
+    }
+
+    // the vertex where the surface crosses the line between two samples
+    pub fn crossing_vertex(&self, a: IVec3, b: IVec3) -> Vertex {
+        self.interpolate_verts(self.to_corner(a), self.to_corner(b))
+    }
+
+    fn interpolate_verts(&self, v1: Vec4, v2: Vec4) -> Vertex {
+        let t = (0.0 - v1.w) / (v2.w - v1.w);
+
+        let position = v1.truncate() + t * (v2.truncate() - v1.truncate());
+        let gradient = self.gradient(v1.truncate().as_ivec3()).lerp(self.gradient(v2.truncate().as_ivec3()), t);
+        let inside = if v1.w >= 0.0 { v1 } else { v2 };
+
+        Vertex {
+            position: (position - PADDING as f32).extend(gradient.length()),
+            normal: (-gradient.normalize()).extend(self.occlusion(inside.truncate().as_ivec3())),
+        }
+    }
+}
+
+// the edges of the crossings around every loop the surface cuts into the faces of a cell,
+// counter clockwise seen from outside. see face_loops in marchig_cubes.wgsl
+pub fn face_loops(values: [f32; 8]) -> Vec<Vec<usize>> {
+    // the crossing that comes after the one on each edge
+    let mut next = [None; 12];
+
+    for (corners, edges) in FACE_CORNERS.iter().zip(FACE_EDGES.iter()) {
+        let c = corners.map(|x| values[x]);
+        let inside = c.map(|x| x >= 0.0);
+        let ambiguous = inside[0] == inside[2] && inside[1] == inside[3] && inside[0] != inside[1];
+
+        // the inside corners of an ambiguous face are connected if the saddle of the bilinear
+        // interpolation is inside too. no division, so both cells come to the exact same result
+        let connected = if inside[0] { c[0] * c[2] >= c[1] * c[3] } else { c[1] * c[3] >= c[0] * c[2] };
+        let leaves = |k: usize| inside[k] && !inside[(k + 1) % 4];
+
+        // the surface enters the face's inside where its edge goes from an outside to an inside corner
+        for k in (0..4).filter(|k| !inside[*k] && inside[(k + 1) % 4]) {
+            let leave = match (ambiguous, connected) {
+                (true, true) => (k + 3) % 4,
+                (true, false) => (k + 1) % 4,
+                (false, _) => (0..4).find(|x| leaves(*x)).unwrap(),
+            };
+            next[edges[k]] = Some(edges[leave]);
+        }
+    }
+
+    let mut loops = Vec::new();
+    let mut visited = [false; 12];
+    for (start, first) in next.iter().enumerate() {
+        let mut current = match first {
+            Some(x) if !visited[start] => *x,
+            _ => continue,
+        };
+
+        let mut cell_loop = vec![start];
+        visited[start] = true;
+        while current != start {
+            cell_loop.push(current);
+            visited[current] = true;
+            current = next[current].unwrap();
+        }
+        loops.push(cell_loop);
+    }
+
+    loops
+}
+
+// a corner of the loop that can be fanned from. a diagonal between two crossings on the same face
+// could show up in the cell across that face as well, so those are left out.
+// None if there is no such corner, the loop then gets a vertex in its middle
+pub fn fan_apex(cell_loop: &[usize]) -> Option<usize> {
+    let n = cell_loop.len();
+    (0..n).find(|apex| {
+        (2..n.saturating_sub(1)).all(|i| EDGE_FACES[cell_loop[*apex]] & EDGE_FACES[cell_loop[(apex + i) % n]] == 0)
+    })
+}
+
+// asymptotic_decider fans the loops of face_loops instead of using TRI_TABLE
+pub fn march(field: Field, asymptotic_decider: bool) -> MeshData {
+    let dims = field.dims;
+    let mut data = MeshData::default();
+    let mut edge_vertices = vec![u32::MAX; dims.buffer_size() * 3];
+
+    let first = field.first();
+    let last = field.last();
+
+    // one vertex per edge crossing
+    for index in 0..dims.buffer_size() {
+        let id = dims.from_index(index);
+        if id.min_element() < first || id.max_element() > last {
+            continue;
+        }
+
+        let v1 = field.to_corner(id);
+
+        for (axis, offset) in AXIS_OFFSETS.iter().enumerate() {
+            let other = id + IVec3::from(*offset);
+            if other.max_element() > last {
+                continue;
+            }
+
+            let v2 = field.to_corner(other);
+            if (v1.w >= 0.0) == (v2.w >= 0.0) {
+                continue;
+            }
+
+            edge_vertices[field.to_edge(id, axis)] = data.vertices.len() as u32;
+            data.vertices.push(field.interpolate_verts(v1, v2));
+        }
+    }
+
+    // triangles referencing the crossings of each cell
+    for y in first..last {
+        for z in first..last {
+            for x in first..last {
+                let id = IVec3::new(x, y, z);
+
+                let mut index = 0;
+                for (i, offset) in CORNER_OFFSETS.iter().enumerate() {
+                    if field.density(id + IVec3::from(*offset)) >= 0.0 {
+                        index |= 1 << i;
+                    }
+                }
+
+                if index == 0x00 || index == 0xff {
+                    continue;
+                }
+
+                let vert = |edge: usize| {
+                    edge_vertices[field.to_edge(id + IVec3::from(EDGE_ORIGIN[edge]), EDGE_AXIS[edge])]
+                };
+
+                if asymptotic_decider {
+                    for cell_loop in face_loops(CORNER_OFFSETS.map(|x| field.density(id + IVec3::from(x)))) {
+                        let n = cell_loop.len();
+                        let corner = |i: usize| vert(cell_loop[i % n]);
+
+                        if let Some(apex) = fan_apex(&cell_loop) {
+                            for i in 1..n - 1 {
+                                data.triangles.push(Triangle {
+                                    a: corner(apex),
+                                    b: corner(apex + i),
+                                    c: corner(apex + i + 1),
+                                });
+                            }
+                            continue;
+                        }
+
+                        let center = data.vertices.len() as u32;
+                        data.vertices.push(loop_center(&data.vertices, (0..n).map(corner)));
+                        for i in 0..n {
+                            data.triangles.push(Triangle {
+                                a: center,
+                                b: corner(i),
+                                c: corner(i + 1),
+                            });
+                        }
+                    }
+                    continue;
+                }
+
+                for tri in TRI_TABLE[index].chunks_exact(3) {
+                    if tri[0] == -1 {
+                        break;
+                    }
+
+                    data.triangles.push(Triangle {
+                        a: vert(tri[0] as usize),
+                        b: vert(tri[1] as usize),
+                        c: vert(tri[2] as usize),
+                    });
+                }
+            }
+        }
+    }
+
+    data
+}
+
+// mean of the loop's vertices, with the normal renormalized
+fn loop_center(vertices: &[Vertex], corners: impl Iterator<Item = u32>) -> Vertex {
+    let mut sum = Vertex { position: Vec4::ZERO, normal: Vec4::ZERO };
+    let mut count = 0.0;
+    for corner in corners {
+        sum.position += vertices[corner as usize].position;
+        sum.normal += vertices[corner as usize].normal;
+        count += 1.0;
+    }
+
+    let normal = sum.normal.truncate().normalize_or_zero().extend(sum.normal.w / count);
+    Vertex { position: sum.position / count, normal }
+}
@@ -1 +1,10 @@
-pub mod chunk;
\ No newline at end of file
+pub mod chunk;
+pub mod extractor;
+pub mod gpu_mesh;
+pub mod lod;
+pub mod mesher;
+pub mod readback;
+pub mod tables;
+
+#[cfg(test)]
+mod tests;
\ No newline at end of file
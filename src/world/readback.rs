@@ -0,0 +1,93 @@
+use bevy::{
+    render::{render_resource::*, renderer::RenderDevice},
+    tasks::{AsyncComputeTaskPool, Task},
+};
+use futures_lite::future;
+use wgpu::{BufferAsyncError, Maintain};
+
+// how many readbacks can be in flight at once, per ring
+pub const RING_SIZE: usize = 4;
+
+enum SlotState {
+    Free,
+    // waiting for the gpu to finish the copy and map the buffer
+    Mapping(Task<Result<(), BufferAsyncError>>),
+}
+
+struct Slot<T> {
+    buffer: Buffer,
+    state: SlotState,
+    payload: Option<T>,
+}
+
+// staging buffers the compute results get copied into, so the results can be read back a few
+// frames later instead of waiting for the gpu right after submitting.
+// payload tells the caller what a result belongs to once it comes back
+pub struct ReadbackRing<T> {
+    slots: Vec<Slot<T>>,
+}
+
+impl<T> ReadbackRing<T> {
+    pub fn new(render_device: &RenderDevice, label: &str, size: u64) -> Self {
+        let slots = (0..RING_SIZE)
+            .map(|_| Slot {
+                buffer: render_device.create_buffer(&BufferDescriptor {
+                    label: Some(label),
+                    size,
+                    usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+                state: SlotState::Free,
+                payload: None,
+            })
+            .collect();
+
+        Self { slots }
+    }
+
+    pub fn free_slot(&self) -> Option<usize> {
+        self.slots.iter().position(|x| matches!(x.state, SlotState::Free))
+    }
+
+    // copy into this, then submit and call map
+    pub fn buffer(&self, slot: usize) -> &Buffer {
+        &self.slots[slot].buffer
+    }
+
+    pub fn in_flight(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter().filter_map(|x| x.payload.as_ref())
+    }
+
+    // has to be called after the commands writing to the slot's buffer got submitted
+    pub fn map(&mut self, slot: usize, payload: T, task_pool: &AsyncComputeTaskPool) {
+        let slot = &mut self.slots[slot];
+        let mapping = slot.buffer.slice(..).map_async(MapMode::Read);
+
+        slot.state = SlotState::Mapping(task_pool.spawn(mapping));
+        slot.payload = Some(payload);
+    }
+
+    // hands every finished result to f, the slots are free again afterwards
+    pub fn collect(&mut self, render_device: &RenderDevice, mut f: impl FnMut(T, &[u8])) {
+        // map_async only makes progress while the device gets polled
+        render_device.poll(Maintain::Poll);
+
+        for slot in self.slots.iter_mut() {
+            let result = match &mut slot.state {
+                SlotState::Mapping(task) => match future::block_on(future::poll_once(task)) {
+                    Some(result) => result,
+                    None => continue,
+                },
+                SlotState::Free => continue,
+            };
+            result.expect("Failed to map staging buffer to host.");
+
+            {
+                let bytes = slot.buffer.slice(..).get_mapped_range();
+                f(slot.payload.take().unwrap(), &bytes);
+            }
+            slot.buffer.unmap();
+            slot.state = SlotState::Free;
+        }
+    }
+}
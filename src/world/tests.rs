@@ -0,0 +1,431 @@
+use bevy::{prelude::*, utils::{HashMap, HashSet}};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::noise::simplex::Simplex;
+
+use super::{
+    chunk::{Chunk, ChunkDims, ChunkMap, MeshCapacity, MeshData, PADDING},
+    lod::{self, ChunkKey, LodSelection},
+    extractor::{DualContouring, MarchingTetrahedra, SurfaceExtractor, SurfaceNets},
+    mesher::{self, Field},
+    tables::{AXIS_OFFSETS, CORNER_OFFSETS, FACE_EDGES},
+};
+
+// small enough to mesh a few dozen fields per test, with an even number of cells for the lods
+const AXIS_SIZE: usize = 19;
+
+// density sampled at every sample of the chunk. the two outermost layers are outside, so every
+// surface closes within the chunk and every edge of the mesh has a neighbour across it
+fn closed_field(dims: ChunkDims, mut density: impl FnMut(Vec3) -> f32) -> Vec<f32> {
+    let last = dims.axis_size as i32 - 1;
+    (0..dims.buffer_size())
+        .map(|i| {
+            let pos = dims.from_index(i);
+            if pos.min_element() <= 1 || pos.max_element() >= last - 1 { -1.0 } else { density(pos.as_vec3()) }
+        })
+        .collect()
+}
+
+fn sphere(dims: ChunkDims) -> Vec<f32> {
+    let center = Vec3::splat(dims.axis_size as f32 * 0.5);
+    closed_field(dims, |pos| 6.3 - pos.distance(center))
+}
+
+// white noise, every ambiguous case shows up a few times
+fn random(dims: ChunkDims, seed: u64) -> Vec<f32> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    closed_field(dims, |_| rng.gen_range(-1.0..1.0))
+}
+
+// rolling terrain with overhangs, cut off at the top and the bottom
+fn smooth(dims: ChunkDims, seed: u64) -> Vec<f32> {
+    let noise = Simplex::new(seed);
+    let ground = dims.axis_size as f32 * 0.5;
+    closed_field(dims, |pos| ground - pos.y + noise.simplex3d(pos * 0.13) * 6.0 + noise.simplex3d(pos * 0.31) * 2.0)
+}
+
+// counts every edge of the triangles by the direction it's walked in
+fn directed_edges(data: &MeshData) -> HashMap<(u32, u32), usize> {
+    let mut edges = HashMap::default();
+    for tri in data.triangles.iter() {
+        for edge in [(tri.a, tri.b), (tri.b, tri.c), (tri.c, tri.a)] {
+            *edges.entry(edge).or_insert(0) += 1;
+        }
+    }
+    edges
+}
+
+// every edge is walked once in each direction, by the two triangles on either side of it.
+// a flipped triangle walks its edges the same way as its neighbours, a hole leaves an edge
+// without its reverse and a seam shared by more than two triangles shows up more than once
+fn assert_closed_manifold(data: &MeshData) {
+    assert!(!data.triangles.is_empty());
+    for tri in data.triangles.iter() {
+        assert!(tri.a != tri.b && tri.b != tri.c && tri.c != tri.a, "degenerate triangle {:?}", tri);
+    }
+
+    let edges = directed_edges(data);
+    for ((a, b), count) in edges.iter() {
+        assert_eq!(*count, 1, "edge {} -> {} is used {} times", a, b, count);
+        assert_eq!(edges.get(&(*b, *a)), Some(&1), "edge {} -> {} has no reverse", a, b);
+    }
+
+    for (vertex, link) in links(data) {
+        assert!(single_fan(&link), "the triangles around {} touch in a point", vertex);
+    }
+}
+
+// the edges opposite of every vertex in the triangles around it, (b, c) for a triangle (vertex, b, c)
+fn links(data: &MeshData) -> HashMap<u32, Vec<(u32, u32)>> {
+    let mut links: HashMap<u32, Vec<(u32, u32)>> = HashMap::default();
+    for tri in data.triangles.iter() {
+        for (a, b, c) in [(tri.a, tri.b, tri.c), (tri.b, tri.c, tri.a), (tri.c, tri.a, tri.b)] {
+            links.entry(a).or_default().push((b, c));
+        }
+    }
+    links
+}
+
+// the triangles around a vertex of a closed manifold go round it once
+fn single_fan(link: &[(u32, u32)]) -> bool {
+    let next: HashMap<u32, u32> = link.iter().copied().collect();
+    let start = link[0].0;
+    let mut current = start;
+    for steps in 1..=link.len() {
+        current = match next.get(&current) {
+            Some(x) => *x,
+            None => return false,
+        };
+        if current == start {
+            return steps == link.len();
+        }
+    }
+    false
+}
+
+// the edges only one triangle walks along, in world space, that lie in the plane x = plane
+fn border_edges(data: &MeshData, key: ChunkKey, dims: ChunkDims, plane: f32) -> Vec<(Vec3, Vec3)> {
+    let edges = directed_edges(data);
+    let world = |x: u32| key.coord.as_vec3() * dims.cells() as f32 + data.vertices[x as usize].position.truncate() * key.scale() as f32;
+
+    edges.keys()
+        .filter(|(a, b)| !edges.contains_key(&(*b, *a)))
+        .map(|(a, b)| (world(*a), world(*b)))
+        .filter(|(a, b)| (a.x - plane).abs() < 1e-4 && (b.x - plane).abs() < 1e-4)
+        .collect()
+}
+
+// counter clockwise seen from outside makes the enclosed volume positive
+fn assert_outward(data: &MeshData) {
+    let position = |x: u32| data.vertices[x as usize].position.truncate();
+    let volume: f32 = data.triangles.iter()
+        .map(|x| position(x.a).dot(position(x.b).cross(position(x.c))))
+        .sum();
+    assert!(volume > 0.0, "the triangles face inwards");
+}
+
+fn assert_welded(data: &MeshData) {
+    let positions: HashSet<[u32; 3]> = data.vertices.iter()
+        .map(|x| x.position.truncate().to_array().map(f32::to_bits))
+        .collect();
+    assert_eq!(positions.len(), data.vertices.len(), "vertices at the same position");
+
+    let used: HashSet<u32> = data.triangles.iter().flat_map(|x| [x.a, x.b, x.c]).collect();
+    assert_eq!(used.len(), data.vertices.len(), "vertices no triangle uses");
+}
+
+#[test]
+fn march_sphere() {
+    let dims = ChunkDims::new(AXIS_SIZE);
+    let points = sphere(dims);
+    for asymptotic_decider in [false, true] {
+        let data = mesher::march(Field::new(&points, dims), asymptotic_decider);
+
+        assert_welded(&data);
+        assert_closed_manifold(&data);
+        assert_outward(&data);
+    }
+}
+
+// neighbours split an ambiguous face the same way with either decider, so no cracks or seams.
+// the classic table only gets the topology wrong, see march_follows_the_saddle
+#[test]
+fn march_random() {
+    let dims = ChunkDims::new(AXIS_SIZE);
+    for asymptotic_decider in [false, true] {
+        for points in (0..20).map(|x| random(dims, x)).chain((0..10).map(|x| smooth(dims, x))) {
+            let data = mesher::march(Field::new(&points, dims), asymptotic_decider);
+
+            assert_welded(&data);
+            assert_closed_manifold(&data);
+            assert_outward(&data);
+        }
+    }
+}
+
+// pieces of the mesh that don't share a vertex
+fn components(data: &MeshData) -> usize {
+    let mut parent: Vec<usize> = (0..data.vertices.len()).collect();
+    fn root(parent: &mut [usize], x: usize) -> usize {
+        let mut x = x;
+        while parent[x] != x {
+            parent[x] = parent[parent[x]];
+            x = parent[x];
+        }
+        x
+    }
+
+    for tri in data.triangles.iter() {
+        for (a, b) in [(tri.a, tri.b), (tri.b, tri.c)] {
+            let (a, b) = (root(&mut parent, a as usize), root(&mut parent, b as usize));
+            parent[a] = b;
+        }
+    }
+    (0..parent.len()).filter(|x| root(&mut parent, *x) == *x).count()
+}
+
+// two solid samples diagonally across a face, the other two corners only just outside. the
+// bilinear saddle in the middle of the face is solid, so they are one blob. the classic table
+// always cuts the face apart, the decider follows the saddle
+#[test]
+fn march_follows_the_saddle() {
+    let dims = ChunkDims::new(AXIS_SIZE);
+    let center = IVec3::splat(dims.axis_size as i32 / 2);
+    let points = closed_field(dims, |pos| {
+        match (pos.as_ivec3() - center).to_array() {
+            [0, 0, 0] | [1, 0, 1] => 1.0,
+            [1, 0, 0] | [0, 0, 1] => -0.1,
+            _ => -1.0,
+        }
+    });
+
+    let classic = mesher::march(Field::new(&points, dims), false);
+    let decider = mesher::march(Field::new(&points, dims), true);
+    for data in [&classic, &decider] {
+        assert_closed_manifold(data);
+        assert_outward(data);
+    }
+    assert_eq!(components(&classic), 2);
+    assert_eq!(components(&decider), 1);
+}
+
+// a fine chunk next to a coarse one, with the sphere poking through the face between them.
+// the open border the fine one leaves in the face is exactly the one of the coarse chunk, walked
+// the other way round
+#[test]
+fn lod_border_is_closed() {
+    let dims = ChunkDims::new(AXIS_SIZE);
+    let cells = dims.cells() as f32;
+    let center = Vec3::new(2.0 * cells, cells * 0.5, cells * 0.5);
+
+    // the +x face of the fine chunk borders the coarse one
+    let fine = ChunkKey::new(IVec3::new(1, 0, 0), 0);
+    let coarse = ChunkKey::new(IVec3::new(2, 0, 0), 1);
+    let plane = 2.0 * cells;
+
+    let sample = |key: ChunkKey| -> Vec<f32> {
+        (0..dims.buffer_size())
+            .map(|i| {
+                let local = dims.from_index(i).as_vec3() - PADDING as f32;
+                let pos = key.coord.as_vec3() * cells + local * key.scale() as f32;
+                6.3 - pos.distance(center)
+            })
+            .collect()
+    };
+
+    let mut fine_points = sample(fine);
+    lod::stitch(&mut fine_points, dims, fine, 1 << 1);
+    let mut fine_mesh = mesher::march(Field::new(&fine_points, dims), false);
+    lod::fill_transitions(&mut fine_mesh, dims, fine, 1 << 1);
+    let coarse_mesh = mesher::march(Field::new(&sample(coarse), dims), false);
+
+    let fine_border = border_edges(&fine_mesh, fine, dims, plane);
+    let coarse_border = border_edges(&coarse_mesh, coarse, dims, plane);
+    assert!(!coarse_border.is_empty());
+    assert_eq!(fine_border.len(), coarse_border.len(), "edges left open along the border");
+
+    for (a, b) in fine_border.iter() {
+        assert!(
+            coarse_border.iter().any(|(x, y)| x.distance(*b) < 1e-3 && y.distance(*a) < 1e-3),
+            "no coarse edge along {} -> {}", a, b
+        );
+    }
+}
+
+// a camera far away wants a coarse chunk where a close one wants a fine chunk with the same coord
+#[test]
+fn chunk_map_keeps_every_lod_of_a_coord() {
+    let dims = ChunkDims::default();
+    let selections = [
+        LodSelection::new(Vec3::ONE, dims, 4),
+        LodSelection::new(Vec3::new(400.0, 1.0, 1.0), dims, 4),
+    ];
+    let fine = ChunkKey::new(IVec3::ZERO, 0);
+    let coarse = ChunkKey::new(IVec3::ZERO, 2);
+    assert!(selections[0].chunks.contains(&fine));
+    assert!(selections[1].chunks.contains(&coarse));
+
+    let mut chunk_map = ChunkMap::default();
+    chunk_map.insert(fine, Entity::from_raw(0));
+    chunk_map.insert(coarse, Entity::from_raw(1));
+    assert_eq!(chunk_map.len(), 2);
+    assert_eq!(chunk_map.get(fine), Some(Entity::from_raw(0)));
+    assert_eq!(chunk_map.get(coarse), Some(Entity::from_raw(1)));
+
+    // each camera replaces the other one's chunk, both stay while their camera wants them
+    assert!(!lod::unloads(&selections, fine, 1));
+    assert!(!lod::unloads(&selections, coarse, 1));
+
+    // the far camera is gone
+    assert!(lod::unloads(&selections[..1], coarse, 1));
+    assert_eq!(chunk_map.remove(coarse), Some(Entity::from_raw(1)));
+    assert_eq!(chunk_map.get(fine), Some(Entity::from_raw(0)));
+    assert_eq!(chunk_map.containing(IVec3::ZERO, 4), Some((fine, Entity::from_raw(0))));
+}
+
+// the terrain changed while the first request was in flight, its readback can land after the second
+#[test]
+fn chunk_drops_stale_density() {
+    let dims = ChunkDims::new(AXIS_SIZE);
+    let mut chunk = Chunk::new_empty(dims, ChunkKey::new(IVec3::ZERO, 0));
+    let old = chunk.request_density();
+    let new = chunk.request_density();
+
+    assert!(chunk.receive_density(new, sphere(dims)));
+    assert!(!chunk.receive_density(old, random(dims, 0)));
+    assert_eq!(chunk.mesh_points(dims).as_ref(), sphere(dims).as_slice());
+}
+
+// a coarse chunk at the origin, a fine one right next to it and an even coarser one further out
+#[test]
+fn chunk_map_finds_the_finest_chunk() {
+    let coarse = ChunkKey::new(IVec3::ZERO, 1);
+    let fine = ChunkKey::new(IVec3::new(2, 0, 0), 0);
+    let coarsest = ChunkKey::new(IVec3::ZERO, 2);
+
+    let mut chunk_map = ChunkMap::default();
+    chunk_map.insert(coarse, Entity::from_raw(0));
+    chunk_map.insert(fine, Entity::from_raw(1));
+    assert_eq!(chunk_map.containing(IVec3::ZERO, 4), Some((coarse, Entity::from_raw(0))));
+    assert_eq!(chunk_map.containing(IVec3::new(1, 1, 1), 4), Some((coarse, Entity::from_raw(0))));
+    assert_eq!(chunk_map.containing(IVec3::new(2, 0, 0), 4), Some((fine, Entity::from_raw(1))));
+    assert_eq!(chunk_map.containing(IVec3::new(3, 0, 0), 4), None);
+    assert_eq!(chunk_map.containing(IVec3::new(-1, 0, 0), 4), None);
+
+    // the finer chunk wins over one of the same area further up, which only fills the gaps
+    chunk_map.insert(coarsest, Entity::from_raw(2));
+    assert_eq!(chunk_map.containing(IVec3::new(1, 1, 1), 4), Some((coarse, Entity::from_raw(0))));
+    assert_eq!(chunk_map.containing(IVec3::new(3, 0, 0), 4), Some((coarsest, Entity::from_raw(2))));
+    assert_eq!(chunk_map.containing(IVec3::new(1, 1, 1), 2), Some((coarse, Entity::from_raw(0))));
+    assert_eq!(chunk_map.containing(IVec3::new(3, 0, 0), 2), None);
+
+    // only the faces towards coarser chunks need stitching, nothing is loaded below or behind
+    assert_eq!(chunk_map.neighbour(fine, 0, 4), Some((coarse, Entity::from_raw(0))));
+    assert_eq!(chunk_map.neighbour(coarse, 1, 4), Some((fine, Entity::from_raw(1))));
+    assert_eq!(chunk_map.transitions(fine, 4), 0b10_1011);
+    assert_eq!(chunk_map.transitions(coarse, 4), 0b10_1000);
+}
+
+#[test]
+fn mesh_capacity_grows_to_fit() {
+    let dims = ChunkDims::default();
+    let capacity = MeshCapacity::new(dims);
+    let worst_case = MeshCapacity::worst_case(dims);
+    assert!(capacity.vertices < worst_case.vertices && capacity.triangles < worst_case.triangles);
+
+    // already fits
+    assert_eq!(capacity.grow_to_fit(dims, 10, 10), capacity);
+
+    // one over doubles both
+    let grown = capacity.grow_to_fit(dims, capacity.vertices as u32 + 1, 0);
+    assert_eq!(grown, MeshCapacity { vertices: capacity.vertices * 2, triangles: capacity.triangles * 2 });
+    assert!(grown.fits(capacity.vertices as u32 + 1, 0));
+
+    // stops at the worst case, even if that's still too small
+    let vertices = worst_case.vertices as u32;
+    assert_eq!(capacity.grow_to_fit(dims, vertices, 0), worst_case);
+    assert_eq!(capacity.grow_to_fit(dims, vertices + 1, 0), worst_case);
+    assert!(!worst_case.fits(vertices + 1, 0));
+}
+
+#[test]
+fn marching_tetrahedra_is_closed() {
+    let dims = ChunkDims::new(AXIS_SIZE);
+    let fields = (0..10).map(|x| smooth(dims, x)).chain((0..10).map(|x| random(dims, x))).chain([sphere(dims)]);
+
+    for points in fields {
+        let data = MarchingTetrahedra.extract(Field::new(&points, dims));
+        assert_welded(&data);
+        assert_closed_manifold(&data);
+        assert_outward(&data);
+    }
+}
+
+// faces where the surface tunnels through, in both cells it crosses the face twice with one sheet.
+// the dual extractors pinch the edge between the two cells' vertices there
+fn tunnel_faces(field: Field) -> usize {
+    let cell_range = field.first() - 1..field.last();
+    let corners = |id: IVec3| CORNER_OFFSETS.map(|x| field.density(id + IVec3::from(x)));
+    let crosses_twice = |id: IVec3, face: usize| {
+        mesher::face_loops(corners(id)).iter().any(|x| FACE_EDGES[face].iter().all(|edge| x.contains(edge)))
+    };
+
+    let mut count = 0;
+    for y in cell_range.clone() {
+        for z in cell_range.clone() {
+            for x in cell_range.clone() {
+                let id = IVec3::new(x, y, z);
+                for (axis, offset) in AXIS_OFFSETS.iter().enumerate() {
+                    let next = id + IVec3::from(*offset);
+                    if cell_range.contains(&next[axis]) && crosses_twice(id, axis * 2 + 1) && crosses_twice(next, axis * 2) {
+                        count += 1;
+                    }
+                }
+            }
+        }
+    }
+    count
+}
+
+// a cell two sheets of the surface pass through gets a vertex for each. a sheet that tunnels
+// through a face still gets pinched into an edge of four triangles, but only there
+#[test]
+fn dual_extractors_pinch_tunnels() {
+    let dims = ChunkDims::new(AXIS_SIZE);
+    let extractors: [Box<dyn SurfaceExtractor>; 2] = [Box::new(DualContouring::default()), Box::new(SurfaceNets)];
+
+    for extractor in extractors.iter() {
+        let points = sphere(dims);
+        let data = extractor.extract(Field::new(&points, dims));
+        assert_closed_manifold(&data);
+        assert_outward(&data);
+
+        let mut tunnels = 0;
+        for points in (0..10).map(|x| smooth(dims, x)).chain((0..10).map(|x| random(dims, x))) {
+            let field = Field::new(&points, dims);
+            let data = extractor.extract(field);
+            let edges = directed_edges(&data);
+            let pinched: HashSet<(u32, u32)> = edges.iter()
+                .filter(|(_, count)| **count > 1)
+                .map(|((a, b), _)| (*a.min(b), *a.max(b)))
+                .collect();
+
+            for ((a, b), count) in edges.iter() {
+                assert!(*count <= 2, "edge {} -> {} is used {} times", a, b, count);
+                assert_eq!(edges.get(&(*b, *a)), Some(count), "edge {} -> {} isn't walked back as often", a, b);
+            }
+            assert_eq!(pinched.len(), tunnel_faces(field), "pinched somewhere else than at a tunnel");
+
+            // everywhere else the triangles go round a vertex once
+            for (vertex, link) in links(&data) {
+                if !pinched.iter().any(|(a, b)| *a == vertex || *b == vertex) {
+                    assert!(single_fan(&link), "the triangles around {} touch in a point", vertex);
+                }
+            }
+
+            assert_outward(&data);
+            tunnels += pinched.len();
+        }
+        assert!(tunnels > 0, "none of the fields has a tunnel, the test doesn't show anything");
+    }
+}